@@ -0,0 +1,70 @@
+//! Cost of sending the game snapshot of a room to all of its users: serialising it for every
+//! recipient (how it used to be done) versus serialising it once and sharing the buffer, and the
+//! shared public view plus the own view of every player serialised as its own small message.
+// the game sources are only partly used here and get linted with the server
+#![allow(unused, clippy::all)]
+
+#[path = "../src/error.rs"]
+mod error;
+#[path = "../src/game/mod.rs"]
+mod game;
+
+use bytestring::ByteString;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use game::{
+    game::{Game, GameConfig},
+    player::Player,
+    view::Snapshots,
+};
+
+/// Running game with `players` birds and some walls on screen
+fn running_game(players: u128) -> Game {
+    let mut game = Game::new(GameConfig::default());
+    for id in 0..players {
+        game.add_player(Player::new(id));
+    }
+    game.start();
+    for _ in 0..10 {
+        game.update(1f32 / 60f32);
+    }
+    game
+}
+
+fn snapshot(c: &mut Criterion) {
+    let mut group = c.benchmark_group("snapshot");
+    for users in [2u128, 8, 32] {
+        let game = running_game(users);
+        group.bench_with_input(BenchmarkId::new("pretty_per_recipient", users), &game, |b, game| {
+            b.iter(|| {
+                (0..users)
+                    .map(|_| serde_json::to_string_pretty(game).unwrap())
+                    .for_each(|x| {
+                        black_box(x);
+                    })
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("once_per_room", users), &game, |b, game| {
+            b.iter(|| {
+                let snapshot = ByteString::from(serde_json::to_string(game).unwrap());
+                (0..users).map(|_| snapshot.clone()).for_each(|x| {
+                    black_box(x);
+                })
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("view_per_player", users), &game, |b, game| {
+            b.iter(|| {
+                let snapshots = Snapshots::new(game);
+                (0..users)
+                    .map(|id| (snapshots.public(), snapshots.own(id)))
+                    .for_each(|x| {
+                        black_box(x);
+                    })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, snapshot);
+criterion_main!(benches);
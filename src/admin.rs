@@ -0,0 +1,123 @@
+use actix::{Addr, MailboxError};
+use actix_web::{
+    dev::Payload, error::InternalError, web::Data, web::Json, web::Path, Error, FromRequest, HttpRequest,
+    HttpResponse,
+};
+use std::future::{ready, Ready};
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::error::LobbyError;
+use crate::game::game::GameConfig;
+use crate::game_lobby::Lobby;
+use crate::messages::{Announce, ForceCloseRoom, ForceDisconnect, ListSessions, ResetRoom, SetGameConfig};
+use crate::socket::WsConn;
+
+/// Proof that the request carries the admin token, add it as argument to a handler to protect it
+pub struct Admin;
+
+impl FromRequest for Admin {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let token = req
+            .app_data::<Data<Config>>()
+            .and_then(|config| config.server.admin_token.clone());
+        let given = req
+            .headers()
+            .get("Authorization")
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.strip_prefix("Bearer "));
+        ready(match (token, given) {
+            // without a token configured there is no admin API
+            (None, _) => Err(InternalError::from_response("", HttpResponse::NotFound().finish()).into()),
+            (Some(token), Some(given)) if same(token.as_bytes(), given.as_bytes()) => Ok(Admin),
+            (Some(_), _) => {
+                warn!(path = req.path(), "admin request without a valid token");
+                let response = HttpResponse::Unauthorized()
+                    .insert_header(("WWW-Authenticate", "Bearer"))
+                    .body("invalid admin token");
+                Err(InternalError::from_response("", response).into())
+            }
+        })
+    }
+}
+
+/// Compare in constant time, so the token can't be guessed byte by byte from the response times
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Id in the path of the request, the path extractor can't do u128
+fn parse_id(path: Path<String>) -> Result<u128, Error> {
+    path.parse()
+        .map_err(|_| actix_web::error::ErrorNotFound(format!("no such id: {}", path)))
+}
+
+/// Answer with what the lobby made of the request
+fn respond(result: Result<Result<(), LobbyError>, MailboxError>) -> HttpResponse {
+    match result {
+        Ok(Ok(())) => HttpResponse::NoContent().finish(),
+        Ok(Err(err @ (LobbyError::RoomNotFound(_) | LobbyError::UserNotFound(_)))) => {
+            HttpResponse::NotFound().body(err.to_string())
+        }
+        Ok(Err(err @ LobbyError::RoundRunning)) => HttpResponse::Conflict().body(err.to_string()),
+        Ok(Err(err)) => HttpResponse::BadRequest().body(err.to_string()),
+        Err(err) => HttpResponse::ServiceUnavailable().body(err.to_string()),
+    }
+}
+
+/// List every session with its room, round trip time and how long it has been idle
+pub async fn list_sessions(_: Admin, srv: Data<Addr<Lobby<WsConn>>>) -> Result<HttpResponse, Error> {
+    let sessions = srv
+        .send(ListSessions)
+        .await
+        .map_err(actix_web::error::ErrorServiceUnavailable)?;
+    Ok(HttpResponse::Ok().json(sessions))
+}
+
+/// Close the connection of a user, their session can't be resumed
+pub async fn disconnect(_: Admin, path: Path<String>, srv: Data<Addr<Lobby<WsConn>>>) -> Result<HttpResponse, Error> {
+    let id = parse_id(path)?;
+    info!(session = %id, "admin disconnects session");
+    Ok(respond(srv.send(ForceDisconnect { id }).await))
+}
+
+/// Disconnect everyone in a room, closing it
+pub async fn close_room(_: Admin, path: Path<String>, srv: Data<Addr<Lobby<WsConn>>>) -> Result<HttpResponse, Error> {
+    let room_id = parse_id(path)?;
+    info!(room = %room_id, "admin closes room");
+    Ok(respond(srv.send(ForceCloseRoom { room_id }).await))
+}
+
+/// Abort the round of a room without results
+pub async fn reset_room(_: Admin, path: Path<String>, srv: Data<Addr<Lobby<WsConn>>>) -> Result<HttpResponse, Error> {
+    let room_id = parse_id(path)?;
+    info!(room = %room_id, "admin resets room");
+    Ok(respond(srv.send(ResetRoom { room_id }).await))
+}
+
+/// Change the rules of a room, only between rounds
+pub async fn set_game_config(
+    _: Admin,
+    path: Path<String>,
+    config: Json<GameConfig>,
+    srv: Data<Addr<Lobby<WsConn>>>,
+) -> Result<HttpResponse, Error> {
+    let room_id = parse_id(path)?;
+    let config = config.into_inner();
+    if let Err(err) = config.validate() {
+        return Ok(HttpResponse::BadRequest().body(err.to_string()));
+    }
+    Ok(respond(srv.send(SetGameConfig { room_id, config }).await))
+}
+
+/// Send the request body to every connected user
+pub async fn announce(_: Admin, message: String, srv: Data<Addr<Lobby<WsConn>>>) -> HttpResponse {
+    let message = message.trim();
+    if message.is_empty() {
+        return HttpResponse::BadRequest().body("announcement is empty");
+    }
+    respond(srv.send(Announce(message.to_string())).await.map(Ok))
+}
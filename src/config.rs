@@ -0,0 +1,415 @@
+use clap::{Parser, ValueEnum};
+use serde::Deserialize;
+use std::{
+    fmt, fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    game::game::GameConfig,
+    game_room::{RoomConfig, MAX_SPECTATOR_DELAY},
+    rate_limit::{Limit, RateLimits},
+    socket::Heartbeat,
+    tls,
+};
+
+/// Settings of the server; defaults, overridden by the TOML file, environment variables and flags in that order
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub heartbeat: HeartbeatConfig,
+    pub rooms: RoomsConfig,
+    pub rate_limits: RateLimitsConfig,
+    pub game: GameConfig, // rules every room starts with
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: String,
+    pub port: u16,
+    pub static_dir: PathBuf, // directory holding index.html and the other client files
+    pub log: String,         // levels per module, e.g. `info,websocket_game::game_room=debug`
+    pub log_format: LogFormat,
+    pub room_threads: Option<usize>, // one per CPU core if not set, 0 keeps the rooms on the thread of the lobby
+    pub tls_cert: Option<PathBuf>, // PEM certificate chain, serve https/wss if given together with the key
+    pub tls_key: Option<PathBuf>,  // PEM private key
+    pub shutdown_grace: u64, // seconds running rounds may go on after SIGTERM
+    pub admin_token: Option<String>, // bearer token of the admin API, disabled if not set
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: String::from("127.0.0.1"),
+            port: 8080,
+            static_dir: PathBuf::from("./static"),
+            log: String::from("info"),
+            log_format: LogFormat::default(),
+            room_threads: None,
+            tls_cert: None,
+            tls_key: None,
+            shutdown_grace: 30,
+            admin_token: None,
+        }
+    }
+}
+
+/// How log lines are written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json, // one JSON object per line, with the fields of the spans it happened in
+}
+
+/// Durations are given in seconds
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeartbeatConfig {
+    pub interval: u64,
+    pub timeout: u64,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        let heartbeat = Heartbeat::default();
+        HeartbeatConfig {
+            interval: heartbeat.interval.as_secs(),
+            timeout: heartbeat.timeout.as_secs(),
+        }
+    }
+}
+
+/// Durations are given in seconds
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoomsConfig {
+    pub tick_rate: u32, // game updates and snapshots per second
+    pub idle_timeout: u64,
+    pub slow_client_timeout: u64,
+    pub spectator_delay: u64,
+    pub max_users: Option<usize>, // users that fit in a room, unlimited if not set
+}
+
+impl Default for RoomsConfig {
+    fn default() -> Self {
+        let room = RoomConfig::default();
+        RoomsConfig {
+            tick_rate: room.tick_rate,
+            idle_timeout: room.idle_timeout.as_secs(),
+            slow_client_timeout: room.slow_timeout.as_secs(),
+            spectator_delay: room.spectator_delay.as_secs(),
+            max_users: room.max_users,
+        }
+    }
+}
+
+/// Sustained messages per second of every connection
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitsConfig {
+    pub input: f64,
+    pub chat: f64,
+    pub command: f64,
+}
+
+impl Default for RateLimitsConfig {
+    fn default() -> Self {
+        let limits = RateLimits::default();
+        RateLimitsConfig {
+            input: limits.input.per_second,
+            chat: limits.chat.per_second,
+            command: limits.command.per_second,
+        }
+    }
+}
+
+/// Command line flags, each can be given as environment variable as well
+#[derive(Debug, Parser)]
+#[command(about = "Multiplayer flappy bird server")]
+pub struct Args {
+    /// TOML file to read the configuration from
+    #[arg(long, env = "CONFIG_FILE")]
+    pub config: Option<PathBuf>,
+    #[arg(long, env = "BIND")]
+    pub bind: Option<String>,
+    #[arg(long, env = "PORT")]
+    pub port: Option<u16>,
+    #[arg(long, env = "STATIC_DIR")]
+    pub static_dir: Option<PathBuf>,
+    #[arg(long, env = "RUST_LOG")]
+    pub log: Option<String>,
+    #[arg(long, env = "LOG_FORMAT")]
+    pub log_format: Option<LogFormat>,
+    #[arg(long, env = "ROOM_THREADS")]
+    pub room_threads: Option<usize>,
+    #[arg(long, env = "TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+    #[arg(long, env = "TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+    #[arg(long, env = "SHUTDOWN_GRACE")]
+    pub shutdown_grace: Option<u64>,
+    #[arg(long, env = "ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
+    #[arg(long, env = "HEARTBEAT_INTERVAL")]
+    pub heartbeat_interval: Option<u64>,
+    #[arg(long, env = "HEARTBEAT_TIMEOUT")]
+    pub heartbeat_timeout: Option<u64>,
+    #[arg(long, env = "TICK_RATE")]
+    pub tick_rate: Option<u32>,
+    #[arg(long, env = "IDLE_TIMEOUT")]
+    pub idle_timeout: Option<u64>,
+    #[arg(long, env = "SLOW_CLIENT_TIMEOUT")]
+    pub slow_client_timeout: Option<u64>,
+    #[arg(long, env = "SPECTATOR_DELAY")]
+    pub spectator_delay: Option<u64>,
+    #[arg(long, env = "MAX_ROOM_USERS")]
+    pub max_room_users: Option<usize>,
+    #[arg(long, env = "RATE_LIMIT_INPUT")]
+    pub rate_limit_input: Option<f64>,
+    #[arg(long, env = "RATE_LIMIT_CHAT")]
+    pub rate_limit_chat: Option<f64>,
+    #[arg(long, env = "RATE_LIMIT_COMMAND")]
+    pub rate_limit_command: Option<f64>,
+    #[arg(long, env = "MAX_JUMPS_PER_SECOND")]
+    pub max_jumps_per_second: Option<usize>,
+    #[arg(long, env = "MAX_INPUT_AGE")]
+    pub max_input_age: Option<u64>,
+    #[arg(long, env = "REWIND_WINDOW")]
+    pub rewind_window: Option<u64>,
+    #[arg(long, env = "REWIND_MARGIN_MS")]
+    pub rewind_margin_ms: Option<u64>,
+}
+
+/// Why the configuration couldn't be loaded
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(&'static str, String), // key, what is wrong with its value
+    Tls(PathBuf, String),          // file, why it can't be used for TLS
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, err) => write!(f, "can't read {}: {}", path.display(), err),
+            ConfigError::Parse(path, err) => write!(f, "can't parse {}: {}", path.display(), err),
+            ConfigError::Invalid(key, reason) => write!(f, "invalid `{}`: {}", key, reason),
+            ConfigError::Tls(path, reason) => write!(f, "can't use {} for TLS: {}", path.display(), reason),
+        }
+    }
+}
+
+/// Replace `field` with `value` if one was given
+fn set<T>(field: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        *field = value;
+    }
+}
+
+/// Check `ok` holds for the value of `key`, with `reason` describing what is expected otherwise
+fn check(ok: bool, key: &'static str, reason: impl Into<String>) -> Result<(), ConfigError> {
+    match ok {
+        true => Ok(()),
+        false => Err(ConfigError::Invalid(key, reason.into())),
+    }
+}
+
+impl Config {
+    /// Load the configuration from the file, environment and flags given to the process and validate it
+    pub fn load() -> Result<Self, ConfigError> {
+        let args = Args::parse();
+        let mut config = match &args.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+        config.apply(args);
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+        toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+    }
+
+    /// Override the settings given as flags or environment variables
+    pub fn apply(&mut self, args: Args) {
+        set(&mut self.server.bind, args.bind);
+        set(&mut self.server.port, args.port);
+        set(&mut self.server.static_dir, args.static_dir);
+        set(&mut self.server.log, args.log);
+        set(&mut self.server.log_format, args.log_format);
+        set(&mut self.server.room_threads, args.room_threads.map(Some));
+        set(&mut self.server.tls_cert, args.tls_cert.map(Some));
+        set(&mut self.server.tls_key, args.tls_key.map(Some));
+        set(&mut self.server.shutdown_grace, args.shutdown_grace);
+        set(&mut self.server.admin_token, args.admin_token.map(Some));
+        set(&mut self.heartbeat.interval, args.heartbeat_interval);
+        set(&mut self.heartbeat.timeout, args.heartbeat_timeout);
+        set(&mut self.rooms.tick_rate, args.tick_rate);
+        set(&mut self.rooms.idle_timeout, args.idle_timeout);
+        set(&mut self.rooms.slow_client_timeout, args.slow_client_timeout);
+        set(&mut self.rooms.spectator_delay, args.spectator_delay);
+        set(&mut self.rooms.max_users, args.max_room_users.map(Some));
+        set(&mut self.rate_limits.input, args.rate_limit_input);
+        set(&mut self.rate_limits.chat, args.rate_limit_chat);
+        set(&mut self.rate_limits.command, args.rate_limit_command);
+        set(&mut self.game.max_jumps_per_second, args.max_jumps_per_second);
+        set(&mut self.game.max_input_age, args.max_input_age);
+        set(&mut self.game.rewind_window, args.rewind_window);
+        set(&mut self.game.rewind_margin_ms, args.rewind_margin_ms);
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        check(self.server.bind.parse::<IpAddr>().is_ok(), "server.bind", "expected an IP address")?;
+        check(
+            self.server.static_dir.join("index.html").is_file(),
+            "server.static_dir",
+            format!("{} holds no index.html", self.server.static_dir.display()),
+        )?;
+        if let Err(err) = EnvFilter::try_new(&self.server.log) {
+            return Err(ConfigError::Invalid("server.log", err.to_string()));
+        }
+        check(
+            self.server.tls_cert.is_some() == self.server.tls_key.is_some(),
+            "server.tls_cert",
+            "server.tls_cert and server.tls_key have to be given together",
+        )?;
+        check(
+            self.server.admin_token.as_ref().is_none_or(|x| x.len() >= 16),
+            "server.admin_token",
+            "must be at least 16 characters",
+        )?;
+        check(self.heartbeat.interval > 0, "heartbeat.interval", "must be at least 1 second")?;
+        check(
+            self.heartbeat.timeout > self.heartbeat.interval,
+            "heartbeat.timeout",
+            "must be longer than heartbeat.interval",
+        )?;
+        check((1..=240).contains(&self.rooms.tick_rate), "rooms.tick_rate", "must be between 1 and 240")?;
+        check(self.rooms.idle_timeout > 0, "rooms.idle_timeout", "must be at least 1 second")?;
+        check(self.rooms.slow_client_timeout > 0, "rooms.slow_client_timeout", "must be at least 1 second")?;
+        check(
+            self.rooms.spectator_delay <= MAX_SPECTATOR_DELAY.as_secs(),
+            "rooms.spectator_delay",
+            format!("must be at most {} seconds", MAX_SPECTATOR_DELAY.as_secs()),
+        )?;
+        check(self.rooms.max_users != Some(0), "rooms.max_users", "must be at least 1")?;
+        for (key, rate) in [
+            ("rate_limits.input", self.rate_limits.input),
+            ("rate_limits.chat", self.rate_limits.chat),
+            ("rate_limits.command", self.rate_limits.command),
+        ] {
+            check(rate.is_finite() && rate > 0.0, key, "must be a positive number")?;
+        }
+        self.game.validate()
+    }
+
+    /// TLS settings read from the certificate and key files, None to serve plain http/ws
+    pub fn tls(&self) -> Result<Option<rustls::ServerConfig>, ConfigError> {
+        match (&self.server.tls_cert, &self.server.tls_key) {
+            (Some(cert), Some(key)) => tls::load(cert, key).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn heartbeat(&self) -> Heartbeat {
+        Heartbeat {
+            interval: Duration::from_secs(self.heartbeat.interval),
+            timeout: Duration::from_secs(self.heartbeat.timeout),
+        }
+    }
+
+    pub fn room_config(&self) -> RoomConfig {
+        RoomConfig {
+            tick_rate: self.rooms.tick_rate,
+            idle_timeout: Duration::from_secs(self.rooms.idle_timeout),
+            slow_timeout: Duration::from_secs(self.rooms.slow_client_timeout),
+            spectator_delay: Duration::from_secs(self.rooms.spectator_delay),
+            max_users: self.rooms.max_users,
+            game: self.game,
+        }
+    }
+
+    pub fn rate_limits(&self) -> RateLimits {
+        RateLimits {
+            input: Limit::per_second(self.rate_limits.input),
+            chat: Limit::per_second(self.rate_limits.chat),
+            command: Limit::per_second(self.rate_limits.command),
+            ..RateLimits::default()
+        }
+    }
+}
+
+impl GameConfig {
+    /// Check the rules make sense, they are validated on their own when an admin changes those of a room
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        check(self.max_jumps_per_second > 0, "game.max_jumps_per_second", "must be at least 1")?;
+        check(
+            self.rewind_window <= self.max_input_age,
+            "game.rewind_window",
+            "must not be longer than game.max_input_age",
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Key the validation of the default config changed by `change` complains about, None if it passes
+    fn invalid(change: impl FnOnce(&mut Config)) -> Option<&'static str> {
+        let mut config = Config::default();
+        change(&mut config);
+        match config.validate() {
+            Ok(()) => None,
+            Err(ConfigError::Invalid(key, _)) => Some(key),
+            Err(err) => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        assert_eq!(invalid(|_| ()), None);
+    }
+
+    #[test]
+    fn refuses_broken_server_settings() {
+        assert_eq!(invalid(|x| x.server.bind = String::from("localhost")), Some("server.bind"));
+        assert_eq!(invalid(|x| x.server.tls_key = Some(PathBuf::from("key.pem"))), Some("server.tls_cert"));
+        assert_eq!(invalid(|x| x.server.admin_token = Some(String::from("short"))), Some("server.admin_token"));
+        assert_eq!(invalid(|x| x.heartbeat.timeout = x.heartbeat.interval), Some("heartbeat.timeout"));
+    }
+
+    #[test]
+    fn refuses_broken_room_settings() {
+        assert_eq!(invalid(|x| x.rooms.tick_rate = 0), Some("rooms.tick_rate"));
+        assert_eq!(invalid(|x| x.rooms.spectator_delay = 61), Some("rooms.spectator_delay"));
+        assert_eq!(invalid(|x| x.rooms.max_users = Some(0)), Some("rooms.max_users"));
+        assert_eq!(invalid(|x| x.rooms.max_users = Some(1)), None);
+    }
+
+    #[test]
+    fn refuses_rates_that_allow_nothing() {
+        assert_eq!(invalid(|x| x.rate_limits.chat = 0.0), Some("rate_limits.chat"));
+        assert_eq!(invalid(|x| x.rate_limits.input = f64::NAN), Some("rate_limits.input"));
+        assert_eq!(invalid(|x| x.rate_limits.command = 0.5), None);
+    }
+
+    #[test]
+    fn refuses_broken_game_rules() {
+        assert_eq!(invalid(|x| x.game.max_jumps_per_second = 0), Some("game.max_jumps_per_second"));
+        assert_eq!(
+            invalid(|x| x.game.rewind_window = x.game.max_input_age + 1),
+            Some("game.rewind_window")
+        );
+    }
+}
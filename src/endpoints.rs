@@ -1,32 +1,172 @@
-use actix::dev::ToEnvelope;
-use actix::{Actor, Addr};
-use actix_web::{get, web::Data, web::Path, web::Payload, Error, HttpRequest, HttpResponse};
+use actix::Addr;
+use actix_web::{
+    dev::ServiceRequest, web::Data, web::Path, web::Payload, web::Query, Error, HttpRequest, HttpResponse,
+};
 use actix_web_actors::ws;
-use uuid::Uuid;
 
 // use crate::lobby::Lobby;
-use crate::game_lobby::Lobby;
-use crate::messages::{Connect, Disconnect, WsMessage};
-use crate::socket::WsConn;
+use crate::game_lobby::{Lobby, DEFAULT_ROOM};
+use crate::game_lobby::READY_TIMEOUT;
+use crate::messages::{CheckReady, FindRoom, ListRooms};
+use crate::metrics::METRICS;
+use crate::rate_limit::RateLimits;
+use crate::room::{parse_code, RoomAccess};
+use crate::socket::{Heartbeat, WsConn, PROTOCOL_VERSION};
+use tracing::{debug, warn};
 
+/// Query parameters carrying credentials, kept out of the access log
+const SECRET_PARAMS: [&str; 4] = ["password", "invite", "resume", "rating_token"];
+
+/// Query string with the values of credentials masked
+fn redact_query(query: &str) -> String {
+    query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((key, _)) if SECRET_PARAMS.contains(&key) => format!("{}=***", key),
+            _ => pair.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Request line for the access log, like `GET /path?query HTTP/1.1` but without credentials in the query
+pub fn request_line(req: &ServiceRequest) -> String {
+    match req.query_string() {
+        "" => format!("{} {} {:?}", req.method(), req.path(), req.version()),
+        query => format!("{} {}?{} {:?}", req.method(), req.path(), redact_query(query), req.version()),
+    }
+}
+
+/// Refuse clients speaking another version of the protocol
+fn check_version(access: &RoomAccess) -> Result<(), Error> {
+    match access.version {
+        Some(version) if version != PROTOCOL_VERSION => Err(actix_web::error::ErrorBadRequest(format!(
+            "unsupported protocol version {}, the server speaks {}",
+            version, PROTOCOL_VERSION
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Id of the room a websocket path segment names by its join code, None for `new`
+async fn resolve_room(segment: &str, srv: &Addr<Lobby<WsConn>>) -> Result<Option<u128>, Error> {
+    if segment == "new" {
+        return Ok(None); // the lobby picks an id that is still free
+    }
+    let code = parse_code(segment)
+        .ok_or_else(|| actix_web::error::ErrorBadRequest(format!("invalid join code: {}", segment)))?;
+    // only rooms that exist can be joined, new ones are opened by the lobby
+    srv.send(FindRoom(code))
+        .await
+        .map_err(actix_web::error::ErrorServiceUnavailable)?
+        .map(Some)
+        .ok_or_else(|| actix_web::error::ErrorNotFound(format!("no such room: {}", segment)))
+}
+
+/// Upgrade to a websocket connection to the room named in the path
 pub async fn start_connection(
     req: HttpRequest,
     stream: Payload,
-    _path: Path<u64>,
+    _path: Path<String>,
+    access: Query<RoomAccess>,
     srv: Data<Addr<Lobby<WsConn>>>,
+    heartbeat: Data<Heartbeat>,
+    limits: Data<RateLimits>,
 ) -> Result<HttpResponse, Error> {
-    println!("Called endpoint!");
+    check_version(&access)?;
+    let group_id = resolve_room(&_path, &srv).await?;
+    debug!(room = ?group_id, role = ?access.role, "websocket connection requested");
+
+    let mut access = access.into_inner();
+    access.address = req.peer_addr().map(|x| x.ip());
+    let ws = WsConn::new(
+        srv.get_ref().clone(),
+        group_id,
+        access,
+        *heartbeat.get_ref(),
+        *limits.get_ref(),
+    );
 
-    let group_id = _path.into_inner();
+    // handshake errors come with their own 4xx status
+    ws::start(ws, &req, stream).inspect_err(|e| warn!(error = %e, "websocket handshake failed"))
+}
+
+/// List the public rooms
+pub async fn list_rooms(srv: Data<Addr<Lobby<WsConn>>>) -> Result<HttpResponse, Error> {
+    let rooms = srv
+        .send(ListRooms)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(rooms))
+}
 
-    let ws = WsConn::new(srv.get_ref().clone(), Some(group_id as u128));
+/// Liveness probe, answered as long as the process serves HTTP
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().body("ok")
+}
 
-    let resp = match ws::start(ws, &req, stream) {
-        Ok(resp) => resp,
-        Err(e) => {
-            println!("WebSocket error: {}", e);
-            HttpResponse::InternalServerError().json(e.to_string())
+/// Readiness probe, fails if the lobby doesn't answer in time, a room's game loop is stuck or the server shuts down
+pub async fn readyz(srv: Data<Addr<Lobby<WsConn>>>) -> HttpResponse {
+    // the lobby waits up to READY_TIMEOUT for the rooms itself
+    match srv.send(CheckReady).timeout(READY_TIMEOUT * 2).await {
+        Ok(readiness) if readiness.ready() => HttpResponse::Ok().json(readiness),
+        Ok(readiness) => {
+            warn!(?readiness, "not ready");
+            HttpResponse::ServiceUnavailable().json(readiness)
+        }
+        Err(err) => {
+            warn!(error = %err, "lobby didn't answer the readiness check");
+            HttpResponse::ServiceUnavailable().body("lobby unresponsive")
         }
+    }
+}
+
+/// Metrics of the server in the Prometheus text format
+pub async fn metrics() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(METRICS.render())
+}
+
+/// Join the quick-play queue, waiting in the default room until matched into a room
+pub async fn start_quickplay(
+    req: HttpRequest,
+    stream: Payload,
+    access: Query<RoomAccess>,
+    srv: Data<Addr<Lobby<WsConn>>>,
+    heartbeat: Data<Heartbeat>,
+    limits: Data<RateLimits>,
+) -> Result<HttpResponse, Error> {
+    check_version(&access)?;
+    // only the name carries over, everything else is up to the room we end up in
+    let access = access.into_inner();
+    let access = RoomAccess {
+        name: access.name,
+        rating_token: access.rating_token,
+        address: req.peer_addr().map(|x| x.ip()),
+        quickplay: true,
+        ..Default::default()
     };
-    Ok(resp)
+    let ws = WsConn::new(
+        srv.get_ref().clone(),
+        Some(DEFAULT_ROOM),
+        access,
+        *heartbeat.get_ref(),
+        *limits.get_ref(),
+    );
+    ws::start(ws, &req, stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credentials_are_masked_in_the_query() {
+        assert_eq!(
+            redact_query("version=1&password=secret&name=ann&invite=abc&resume=tok&rating_token=x"),
+            "version=1&password=***&name=ann&invite=***&resume=***&rating_token=***"
+        );
+        assert_eq!(redact_query("visibility=private&role"), "visibility=private&role");
+    }
 }
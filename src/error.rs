@@ -0,0 +1,69 @@
+use std::fmt;
+
+use crate::game::input::InputError;
+
+/// Everything that can go wrong handling a message in the lobby, sent back to the user who caused it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LobbyError {
+    RoomNotFound(u128),
+    NotInRoom,
+    UserNotFound(u128),
+    UserNotInRoom(u128),
+    Mailbox(String), // couldn't deliver the message to the actor of the user
+    NotHost,
+    WaitingRoom,
+    TargetSelf,
+    Banned,
+    Locked,
+    PrivateRoom,
+    WrongPassword,
+    RoomFull,
+    ShuttingDown,
+    Spectating,
+    RoundRunning,
+    InvalidResumeToken,
+    SessionExpired,
+    NameTaken(String),
+    AlreadyQueued,
+    NotQueued,
+    UnknownVisibility(String),
+    UnknownCommand(String),
+    Usage(&'static str),
+    InvalidInput(String),
+    InputRejected(InputError),
+}
+
+impl fmt::Display for LobbyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LobbyError::RoomNotFound(id) => write!(f, "Room {} not found", id),
+            LobbyError::NotInRoom => write!(f, "You are not in this room"),
+            LobbyError::UserNotFound(id) => write!(f, "User {} not found", id),
+            LobbyError::UserNotInRoom(id) => write!(f, "No user with id {} in the room", id),
+            LobbyError::Mailbox(err) => write!(f, "Message can't be delivered: {}", err),
+            LobbyError::NotHost => write!(f, "Only the host can do that"),
+            LobbyError::WaitingRoom => write!(f, "The quick-play waiting room has no host"),
+            LobbyError::TargetSelf => write!(f, "Can't do that to yourself"),
+            LobbyError::Banned => write!(f, "You are banned from this room"),
+            LobbyError::Locked => write!(f, "Room is locked"),
+            LobbyError::PrivateRoom => write!(f, "Room is private, an invite code or password is required"),
+            LobbyError::WrongPassword => write!(f, "Wrong password"),
+            LobbyError::RoomFull => write!(f, "Room is full"),
+            LobbyError::ShuttingDown => write!(f, "Server is shutting down"),
+            LobbyError::Spectating => write!(f, "Spectators can't play"),
+            LobbyError::RoundRunning => write!(f, "A round is running, try again once it is over"),
+            LobbyError::InvalidResumeToken => write!(f, "Invalid resume token"),
+            LobbyError::SessionExpired => write!(f, "Session expired"),
+            LobbyError::NameTaken(name) => write!(f, "Name {} is taken, use its rating token or pick another one", name),
+            LobbyError::AlreadyQueued => write!(f, "Already waiting for a quick-play room"),
+            LobbyError::NotQueued => write!(f, "Not waiting for a quick-play room"),
+            LobbyError::UnknownVisibility(x) => write!(f, "Unknown visibility: {}", x),
+            LobbyError::UnknownCommand(x) => write!(f, "Unknown command: {}", x),
+            LobbyError::Usage(usage) => write!(f, "usage: {}", usage),
+            LobbyError::InvalidInput(x) => write!(f, "Input can't be parsed: {}", x),
+            LobbyError::InputRejected(e) => write!(f, "Input rejected: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for LobbyError {}
@@ -1,47 +1,138 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use rand::prelude::*;
 use rand::distributions::{Distribution, Uniform};
 
 use super::objects::traits::Position;
 use super::{
+    input::{Input, InputError},
     objects::{bird::Bird, traits::GameObject, traits::IGameObject, wall::Wall, collision::collision_circle_rectange},
     player::Player,
 };
 use itertools::Itertools;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use tracing::trace;
 use uuid::Uuid;
 
+/// Rules of a game that can differ between rooms
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GameConfig {
+    pub max_jumps_per_second: usize,
+    pub max_input_age: u64, // inputs tagged with a tick older than this many ticks are rejected
+    pub rewind_window: u64, // late jumps up to this many ticks old are applied at the tick the client saw
+    pub rewind_margin_ms: u64, // on top of the round trip time of the player, for how long a jump can be rewound
+}
+
+impl Default for GameConfig {
+    fn default() -> Self {
+        GameConfig {
+            max_jumps_per_second: 8,
+            max_input_age: 30,
+            rewind_window: 12,
+            rewind_margin_ms: 50,
+        }
+    }
+}
+
+/// Suspicious inputs kept per player, older ones are only counted
+const SUSPICIOUS_KEPT: usize = 16;
+
+/// Suspicious inputs of a player
+#[derive(Debug, Clone, Default)]
+pub struct Suspicion {
+    pub count: u64, // suspicious inputs so far
+    pub recent: VecDeque<(u64, InputError)>, // tick and reason of the last few, oldest first
+}
+
+/// State of the birds and walls after a tick, kept to rewind birds for late inputs
+#[derive(Debug, Clone)]
+struct Frame {
+    tick: u64,
+    dt: f32,
+    birds: HashMap<u128, Bird>,
+    walls: Vec<Wall>,
+    jumped: HashSet<u128>, // birds that jumped in this tick
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct Game {
     pub running: bool,
+    pub tick: u64, // number of updates simulated, clients tag their inputs with the last tick they saw
     players: HashMap<u128, Player>,
     // birds: Vec<Bird>,
     pub birds: HashMap<u128, Bird>,
     pub walls: Vec<Wall>,
+    #[serde(skip)]
+    deaths: Vec<Vec<u128>>, // player ids of the birds dying each update, in order of death
+    #[serde(skip)]
+    results: Option<Vec<Vec<u128>>>, // placements of the last finished round, best first
+    #[serde(skip)]
+    pub config: GameConfig,
+    #[serde(skip)]
+    time: f32, // seconds simulated
+    #[serde(skip)]
+    jumps: HashMap<u128, VecDeque<f32>>, // player id -> time of the jumps in the last second
+    #[serde(skip)]
+    suspicious: HashMap<u128, Suspicion>, // player id -> suspicious inputs
+    #[serde(skip)]
+    history: VecDeque<Frame>, // the ticks within the rewind window, oldest first
 }
 
 impl Game {
-    pub fn new() -> Game {
+    pub fn new(config: GameConfig) -> Game {
         Game {
             running: false,
+            tick: 0,
             players: HashMap::new(),
             // birds: Vec::new(),
             birds: HashMap::new(),
             walls: Vec::new(),
+            deaths: Vec::new(),
+            results: None,
+            config,
+            time: 0.0,
+            jumps: HashMap::new(),
+            suspicious: HashMap::new(),
+            history: VecDeque::new(),
         }
     }
+    fn new_bird() -> Bird {
+        Bird::new([0.2f32, 0.5f32], [0f32, 0f32], 0.025)
+    }
     pub fn add_player(&mut self, player: Player) -> u128 {
         self.players.insert(player.id, player);
-        let bird = Bird::new([0.2f32, 0.5f32], [0f32, 0f32], 0.025);
+        let bird = Game::new_bird();
         self.birds.insert(player.id, bird.clone());
         // self.birds.push(bird);
         return bird.id();
     }
+    pub fn remove_player(&mut self, id: u128) -> Option<Player> {
+        self.birds.remove(&id);
+        self.jumps.remove(&id);
+        self.suspicious.remove(&id);
+        self.players.remove(&id)
+    }
     pub fn update(&mut self, dt: f32) {
         // Don't do shit if not running
         if !self.running {
             return;
         }
+        self.tick += 1;
+        self.time += dt;
+
+        let alive = self
+            .birds
+            .iter()
+            .filter(|(_, bird)| bird.active())
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+
+        let jumped = self
+            .birds
+            .iter()
+            .filter(|(_, bird)| bird.active() && bird.jumping())
+            .map(|(id, _)| *id)
+            .collect::<HashSet<_>>();
 
         for object in self.birds.values_mut() {
             object.update(dt);
@@ -51,14 +142,32 @@ impl Game {
         }
 
         self.birds.values_mut().for_each(|bird| {
-            for w in self.walls.iter() {
-                if collision_circle_rectange(bird.clone(), w.clone()) {
-                    println!("collision between BIRB[{:?}] and WALL[{:?}]", &bird, &w);
-                    bird.set_active(false);
-                    break;
-                }
-            }
-        });//.collect::<Vec<_>>();
+            Game::check_crash(bird, &self.walls);
+            Game::check_bounds(bird);
+        });
+
+        self.history.push_back(Frame {
+            tick: self.tick,
+            dt,
+            birds: self.birds.clone(),
+            walls: self.walls.clone(),
+            jumped,
+        });
+        while self.history.len() as u64 > self.config.rewind_window + 1 {
+            self.history.pop_front();
+        }
+
+        let died = alive
+            .into_iter()
+            .filter(|id| !self.birds[id].active())
+            .collect::<Vec<_>>();
+        if !died.is_empty() {
+            self.deaths.push(died);
+        }
+        if !self.birds.is_empty() && self.birds.values().all(|bird| !bird.active()) {
+            self.finish();
+            return;
+        }
 
         self.walls.retain(|x| x.position()[0] > -0.2); // keep elements where this is true
 
@@ -70,6 +179,90 @@ impl Game {
         }
     }
 
+    /// Kill the bird if it hit a wall
+    fn check_crash(bird: &mut Bird, walls: &[Wall]) {
+        for w in walls.iter() {
+            if collision_circle_rectange(*bird, *w) {
+                trace!(position = ?bird.position(), wall = ?w.position(), "bird crashed into a wall");
+                bird.set_active(false);
+                return;
+            }
+        }
+    }
+
+    /// Kill the bird if it left the screen vertically, nothing could hit it out there and the round would never end
+    fn check_bounds(bird: &mut Bird) {
+        if !(0f32..=1f32).contains(&bird.position()[1]) {
+            trace!(position = ?bird.position(), "bird left the screen");
+            bird.set_active(false);
+        }
+    }
+
+    /// State of the bird of player `id` after `tick`, if it is still in the history
+    fn bird_at(&self, id: u128, tick: u64) -> Option<&Bird> {
+        self.history
+            .iter()
+            .find(|frame| frame.tick == tick)
+            .and_then(|frame| frame.birds.get(&id))
+    }
+
+    /// Check if a jump of player `id` tagged with `tick` may still be applied in the past: the tick has to be
+    /// no older than the round trip time of the player and a small margin, a client can't have seen it later
+    fn can_rewind(&self, id: u128, tick: u64) -> bool {
+        let rtt = self.players.get(&id).and_then(|x| x.ping).unwrap_or(0) as u64;
+        let limit = (rtt + self.config.rewind_margin_ms) as f32 / 1000f32;
+        let age: f32 = self
+            .history
+            .iter()
+            .filter(|frame| frame.tick > tick)
+            .map(|frame| frame.dt)
+            .sum();
+        age <= limit
+    }
+
+    /// Apply a jump of player `id` sent after seeing `tick` at the tick after it, resimulating the bird since.
+    /// Returns false if `tick` isn't in the history anymore
+    fn rewind_jump(&mut self, id: u128, tick: u64) -> bool {
+        let start = match self.history.iter().position(|frame| frame.tick == tick) {
+            Some(start) => start,
+            None => return false,
+        };
+        let mut bird = match self.history[start].birds.get(&id) {
+            Some(bird) => *bird,
+            None => return false,
+        };
+        bird.set_input(true);
+        for frame in self.history.iter_mut().skip(start + 1) {
+            if bird.jumping() || frame.jumped.contains(&id) {
+                bird.set_input(true);
+                frame.jumped.insert(id);
+            }
+            bird.update(frame.dt);
+            Game::check_crash(&mut bird, &frame.walls);
+            Game::check_bounds(&mut bird);
+            frame.birds.insert(id, bird);
+        }
+
+        let current = match self.birds.get_mut(&id) {
+            Some(current) => current,
+            None => return false,
+        };
+        let was_active = current.active();
+        bird.set_input(current.jumping()); // keep the input waiting for the next tick
+        *current = bird;
+        if !was_active && bird.active() {
+            // the jump saved the bird after all
+            self.deaths.iter_mut().for_each(|x| x.retain(|x| *x != id));
+            self.deaths.retain(|x| !x.is_empty());
+        } else if was_active && !bird.active() {
+            self.deaths.push(vec![id]);
+            if self.birds.values().all(|bird| !bird.active()) {
+                self.finish();
+            }
+        }
+        true
+    }
+
     fn check_start(&self) -> bool {
         if self.players.len() > 0 {
             if self.players.values().all(|x| x.ready) {
@@ -93,10 +286,268 @@ impl Game {
         self.running = true;
     }
 
-    pub fn set_input(&mut self, id: u128, input: bool) -> Option<()> {
-        // let player = self.players.iter_mut().find(|player| player.id() == player_id).unwrap();
-        // player.set_input(input);
-        return self.birds.get_mut(&id).map(|b| b.set_input(input)); // try to find bird -> if found set input
-                                                                    // bird.set_input(input);
+    /// End the round once every bird died -> the last bird standing wins.
+    /// Players get a fresh bird and have to ready up again for the next round
+    fn finish(&mut self) {
+        self.results = Some(self.deaths.drain(..).rev().collect());
+        self.clear_round();
+    }
+
+    /// Abort the round without results, players get a fresh bird and have to ready up again
+    pub fn reset(&mut self) {
+        self.deaths.clear();
+        self.clear_round();
+    }
+
+    fn clear_round(&mut self) {
+        self.running = false;
+        self.walls.clear();
+        self.jumps.clear();
+        self.history.clear();
+        for player in self.players.values_mut() {
+            player.set_ready(false);
+            self.birds.insert(player.id, Game::new_bird());
+        }
+    }
+
+    /// End the round right away, birds still flying share the first place
+    pub fn end_round(&mut self) {
+        if !self.running {
+            return;
+        }
+        let alive = self
+            .birds
+            .iter()
+            .filter(|(_, bird)| bird.active())
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        if !alive.is_empty() {
+            self.deaths.push(alive);
+        }
+        self.finish();
+    }
+
+    /// Placements of the last finished round, best first with ties sharing a place
+    pub fn take_results(&mut self) -> Option<Vec<Vec<u128>>> {
+        self.results.take()
+    }
+
+    pub fn players(&self) -> &HashMap<u128, Player> {
+        &self.players
+    }
+
+    pub fn set_ping(&mut self, id: u128, ping: u32) -> Option<()> {
+        self.players.get_mut(&id).map(|x| x.ping = Some(ping))
+    }
+
+    /// Check if player `id` has a bird still in the game
+    pub fn is_alive(&self, id: u128) -> bool {
+        self.birds.get(&id).is_some_and(|bird| bird.active())
+    }
+
+    /// Suspicious inputs of player `id`, None if there were none
+    pub fn suspicious(&self, id: u128) -> Option<&Suspicion> {
+        self.suspicious.get(&id)
+    }
+
+    /// Set the jump-state of the bird of player `id` if the input is valid
+    pub fn set_input(&mut self, id: u128, input: Input) -> Result<(), InputError> {
+        let result = self.validate_input(id, input);
+        // rejected inputs count as processed too, the client shouldn't predict them any longer
+        if let (Some(seq), Some(player)) = (input.seq, self.players.get_mut(&id)) {
+            player.seq = Some(player.seq.map_or(seq, |x| x.max(seq)));
+        }
+        match result {
+            Ok(()) => {
+                let late = input
+                    .tick
+                    .filter(|tick| input.jump && *tick < self.tick && self.can_rewind(id, *tick));
+                if !late.is_some_and(|tick| self.rewind_jump(id, tick)) {
+                    if let Some(bird) = self.birds.get_mut(&id) {
+                        bird.set_input(input.jump);
+                    }
+                }
+            }
+            Err(e) if e.is_suspicious() => {
+                let suspicion = self.suspicious.entry(id).or_default();
+                suspicion.count += 1;
+                if suspicion.recent.len() == SUSPICIOUS_KEPT {
+                    suspicion.recent.pop_front();
+                }
+                suspicion.recent.push_back((self.tick, e));
+            }
+            Err(_) => (),
+        }
+        result
+    }
+
+    fn validate_input(&mut self, id: u128, input: Input) -> Result<(), InputError> {
+        let player = self.players.get(&id).ok_or(InputError::NotPlaying)?;
+        if let (Some(seq), Some(last)) = (input.seq, player.seq) {
+            if seq <= last {
+                return Err(InputError::OldSequence(seq));
+            }
+        }
+        if !self.running {
+            return Err(InputError::NotRunning);
+        }
+        // a late jump may still save a bird that died since the tick the client saw
+        let alive_then = input
+            .tick
+            .filter(|tick| self.can_rewind(id, *tick))
+            .and_then(|tick| self.bird_at(id, tick))
+            .is_some_and(|bird| input.jump && bird.active());
+        if !self.is_alive(id) && !alive_then {
+            return Err(InputError::Dead);
+        }
+        if let Some(tick) = input.tick {
+            if tick > self.tick {
+                return Err(InputError::FutureTick(tick));
+            }
+            if self.tick - tick > self.config.max_input_age {
+                return Err(InputError::StaleTick(tick));
+            }
+        }
+        if input.jump {
+            let now = self.time;
+            let jumps = self.jumps.entry(id).or_default();
+            while jumps.front().is_some_and(|x| now - x >= 1.0) {
+                jumps.pop_front();
+            }
+            if jumps.len() >= self.config.max_jumps_per_second {
+                return Err(InputError::TooManyJumps);
+            }
+            jumps.push_back(now);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 1f32 / 60f32;
+
+    /// Running game of two players with `ping` milliseconds, the second keeps the round going
+    fn running_game(ping: u32) -> Game {
+        let mut game = Game::new(GameConfig {
+            rewind_margin_ms: 20,
+            ..GameConfig::default()
+        });
+        for id in [1, 2] {
+            game.add_player(Player::new(id));
+            game.set_ping(id, ping);
+        }
+        game.start();
+        game
+    }
+
+    /// Run the game up to `tick`, killing the bird of player 1 right after `death` if given
+    fn run(game: &mut Game, tick: u64, death: Option<u64>) {
+        while game.tick < tick {
+            game.update(DT);
+            if Some(game.tick) == death {
+                game.birds.get_mut(&1).unwrap().set_active(false);
+                game.history.back_mut().unwrap().birds.get_mut(&1).unwrap().set_active(false);
+            }
+        }
+    }
+
+    fn jump(tick: u64) -> Input {
+        Input {
+            jump: true,
+            tick: Some(tick),
+            seq: None,
+        }
+    }
+
+    fn rewound(game: &Game, tick: u64) -> bool {
+        game.history
+            .iter()
+            .find(|frame| frame.tick == tick + 1)
+            .is_some_and(|frame| frame.jumped.contains(&1))
+    }
+
+    #[test]
+    fn round_ends_once_the_birds_left_the_screen() {
+        let mut game = running_game(0);
+        // without walls only leaving the screen can kill the birds
+        while game.running && game.tick < 600 {
+            game.update(DT);
+            game.walls.clear();
+        }
+        assert!(!game.running);
+        assert_eq!(game.take_results().map(|x| x.concat().len()), Some(2));
+    }
+
+    #[test]
+    fn suspicious_inputs_are_counted_but_only_the_last_are_kept() {
+        let mut game = running_game(0);
+        let flood = Input {
+            jump: true,
+            tick: None,
+            seq: None,
+        };
+        for _ in 0..100 {
+            game.set_input(1, flood).unwrap_or(());
+        }
+        game.set_input(1, jump(game.tick + 1)).unwrap_or(());
+        let suspicion = game.suspicious(1).unwrap();
+        assert_eq!(suspicion.count, 100 - game.config.max_jumps_per_second as u64 + 1);
+        assert_eq!(suspicion.recent.len(), SUSPICIOUS_KEPT);
+        assert_eq!(suspicion.recent.back(), Some(&(0, InputError::FutureTick(1))));
+        assert!(game.suspicious(2).is_none());
+    }
+
+    #[test]
+    fn late_jump_within_round_trip_is_rewound() {
+        let mut game = running_game(100);
+        run(&mut game, 10, None);
+        assert_eq!(game.set_input(1, jump(5)), Ok(()));
+        assert!(rewound(&game, 5));
+    }
+
+    #[test]
+    fn late_jump_saves_bird_dying_within_round_trip() {
+        let mut game = running_game(100);
+        run(&mut game, 10, Some(7));
+        assert!(!game.is_alive(1));
+        assert_eq!(game.set_input(1, jump(6)), Ok(()));
+        assert!(game.is_alive(1));
+    }
+
+    #[test]
+    fn jump_older_than_round_trip_is_applied_now() {
+        let mut game = running_game(30);
+        run(&mut game, 10, None);
+        assert_eq!(game.set_input(1, jump(5)), Ok(()));
+        assert!(!rewound(&game, 5));
+        assert!(game.birds[&1].jumping());
+    }
+
+    #[test]
+    fn jump_older_than_round_trip_cant_revive() {
+        let mut game = running_game(30);
+        run(&mut game, 10, Some(7));
+        assert_eq!(game.set_input(1, jump(5)), Err(InputError::Dead));
+        assert!(!game.is_alive(1));
+    }
+
+    #[test]
+    fn jump_older_than_window_cant_revive() {
+        let mut game = running_game(1000);
+        let window = game.config.rewind_window;
+        run(&mut game, window + 10, Some(window + 5));
+        assert_eq!(game.set_input(1, jump(4)), Err(InputError::Dead));
+        assert!(!game.is_alive(1));
+    }
+
+    #[test]
+    fn jump_of_bird_dead_at_that_tick_cant_revive() {
+        let mut game = running_game(1000);
+        run(&mut game, 10, Some(5));
+        assert_eq!(game.set_input(1, jump(7)), Err(InputError::Dead));
+        assert!(!game.is_alive(1));
     }
 }
@@ -0,0 +1,61 @@
+use std::{fmt, str::FromStr};
+
+use crate::error::LobbyError;
+
+/// Input of a player: `<jump> [tick] [seq]`, e.g. `true 1234 17`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Input {
+    pub jump: bool,
+    pub tick: Option<u64>, // last tick the client saw when sending the input
+    pub seq: Option<u64>,  // sequence number of the input, increasing with every input of the client
+}
+
+impl FromStr for Input {
+    type Err = LobbyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || LobbyError::InvalidInput(s.to_string());
+        let mut args = s.split_whitespace();
+        let jump = args.next().and_then(|x| x.parse().ok()).ok_or_else(invalid)?;
+        let mut number = || args.next().map(|x| x.parse::<u64>().map_err(|_| invalid())).transpose();
+        let tick = number()?;
+        let seq = number()?;
+        if args.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(Input { jump, tick, seq })
+    }
+}
+
+/// Why an input of a player was rejected by the game
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputError {
+    NotPlaying, // no player with this id in the game
+    NotRunning,
+    Dead,
+    FutureTick(u64), // tick the client can't have seen yet
+    StaleTick(u64),  // tick too far in the past
+    TooManyJumps,
+    OldSequence(u64), // sequence number the server already processed
+}
+
+impl InputError {
+    /// Rejections an honest client can't cause, these end up in the suspicious-behaviour log
+    pub fn is_suspicious(&self) -> bool {
+        matches!(self, InputError::FutureTick(_) | InputError::TooManyJumps)
+    }
+}
+
+impl fmt::Display for InputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InputError::NotPlaying => write!(f, "not playing"),
+            InputError::NotRunning => write!(f, "game is not running"),
+            InputError::Dead => write!(f, "bird is dead"),
+            InputError::FutureTick(tick) => write!(f, "tick {} is in the future", tick),
+            InputError::StaleTick(tick) => write!(f, "tick {} is too old", tick),
+            InputError::TooManyJumps => write!(f, "too many jumps"),
+            InputError::OldSequence(seq) => write!(f, "input {} was already processed", seq),
+        }
+    }
+}
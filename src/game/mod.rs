@@ -1,3 +1,5 @@
 pub mod game;
+pub mod input;
 pub mod objects;
 pub mod player;
+pub mod view;
@@ -32,6 +32,15 @@ impl Bird {
         self.active = _active;
         self.active
     }
+
+    pub fn active(&self) -> bool {
+        self.active
+    }
+
+    /// Will the bird jump in the next update?
+    pub fn jumping(&self) -> bool {
+        self.jump
+    }
 }
 
 impl IGameObject for Bird {
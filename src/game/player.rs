@@ -4,6 +4,8 @@ use serde::Serialize;
 pub struct Player {
     pub id: u128,
     pub ready: bool,
+    pub ping: Option<u32>, // round trip time to the client in milliseconds
+    pub seq: Option<u64>,  // sequence number of the last input processed, clients reconcile their prediction with it
 }
 
 impl Player {
@@ -11,6 +13,8 @@ impl Player {
         Player {
             id,
             ready: false,
+            ping: None,
+            seq: None,
         }
     }
     pub fn set_ready(&mut self, ready: bool) -> bool {
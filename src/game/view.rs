@@ -0,0 +1,130 @@
+use bytestring::ByteString;
+use serde::Serialize;
+use std::collections::HashMap;
+
+use super::{
+    game::Game,
+    objects::traits::{Circle, Position, Rectange, Velocity},
+};
+
+#[derive(Debug, Serialize)]
+struct PlayerView {
+    ready: bool,
+    ping: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+struct BirdView {
+    position: [f32; 2],
+    velocity: [f32; 2],
+    radius: f32,
+    active: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct WallView {
+    position: [f32; 2],
+    velocity: [f32; 2],
+    width: f32,
+    height: f32,
+}
+
+/// What everyone in the room gets to see of the game, leaving out internal ids and pending inputs
+#[derive(Debug, Serialize)]
+pub struct GameView {
+    running: bool,
+    tick: u64,
+    players: HashMap<u128, PlayerView>,
+    birds: HashMap<u128, BirdView>, // keyed by the id of the player owning the bird
+    walls: Vec<WallView>,
+}
+
+impl GameView {
+    pub fn of(game: &Game) -> Self {
+        GameView {
+            running: game.running,
+            tick: game.tick,
+            players: game
+                .players()
+                .iter()
+                .map(|(id, x)| (*id, PlayerView { ready: x.ready, ping: x.ping }))
+                .collect(),
+            birds: game
+                .birds
+                .iter()
+                .map(|(id, x)| {
+                    let bird = BirdView {
+                        position: x.position(),
+                        velocity: x.velocity(),
+                        radius: x.radius(),
+                        active: x.active(),
+                    };
+                    (*id, bird)
+                })
+                .collect(),
+            walls: game
+                .walls
+                .iter()
+                .map(|x| WallView {
+                    position: x.position(),
+                    velocity: x.velocity(),
+                    width: x.width(),
+                    height: x.height(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// What only the player themself gets to see, to reconcile their prediction
+#[derive(Debug, Serialize)]
+pub struct OwnView {
+    id: u128,
+    seq: Option<u64>, // last input processed
+    jump: bool,       // input waiting for the next tick
+}
+
+impl OwnView {
+    pub fn of(game: &Game, id: u128) -> Option<Self> {
+        let player = game.players().get(&id)?;
+        Some(OwnView {
+            id,
+            seq: player.seq,
+            jump: game.birds.get(&id).is_some_and(|x| x.jumping()),
+        })
+    }
+}
+
+/// Own view of a player sent as its own small message, next to the public snapshot of the same tick
+#[derive(Debug, Serialize)]
+struct OwnSnapshot {
+    tick: u64,
+    you: OwnView,
+}
+
+/// Snapshots of the game at one tick, the public view is serialised once and shared by every recipient
+pub struct Snapshots<'a> {
+    game: &'a Game,
+    public: ByteString,
+}
+
+impl<'a> Snapshots<'a> {
+    pub fn new(game: &'a Game) -> Self {
+        Snapshots {
+            game,
+            public: serde_json::to_string(&GameView::of(game)).unwrap().into(),
+        }
+    }
+
+    /// Public snapshot, the same buffer for everyone in the room
+    pub fn public(&self) -> ByteString {
+        self.public.clone()
+    }
+
+    /// Own view of the player `id`, only the few bytes of it are serialised per recipient
+    pub fn own(&self, id: u128) -> Option<ByteString> {
+        let you = OwnView::of(self.game, id)?;
+        let own = OwnSnapshot { tick: self.game.tick, you };
+        Some(serde_json::to_string(&own).unwrap().into())
+    }
+}
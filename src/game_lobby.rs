@@ -1,268 +1,1217 @@
 // crate::messages::{ClientActorMessage, Connect, Disconnect, WsMessage};
 use actix::{
     dev::ToEnvelope,
-    prelude::{Actor, Context, Handler, Recipient},
-    Addr, AsyncContext, Message,
+    prelude::{Actor, Context, Handler},
+    Addr, ArbiterHandle, AsyncContext, MessageResult, Recipient, ResponseFuture,
 };
-use actix_web_actors::ws;
+use actix_web_actors::ws::{CloseCode, CloseReason};
 use itertools::Itertools;
 use std::{
     collections::{HashMap, HashSet},
+    net::IpAddr,
     time::{Duration, Instant},
 };
-use uuid::Uuid;
+use tokio::sync::oneshot;
+use tracing::{debug, info};
 
 // mod messages;
-use crate::game::game::Game;
-use crate::{game::player::Player, messages::*};
+use crate::{
+    error::LobbyError,
+    game_room::{GameRoom, RoomConfig, MAX_SPECTATOR_DELAY},
+    matchmaking::Matchmaker,
+    messages::*,
+    metrics::METRICS,
+    rating::{Rating, Ratings},
+    session::{ResumableSessions, SessionInfo},
+    room::{random_code, Banned, Role, Room, RoomAccess, Visibility},
+};
 
 pub struct Lobby<A>
 where
-    A: Actor + Handler<WsMessage>,
-    <A as Actor>::Context: ToEnvelope<A, WsMessage>,
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
 {
     pub sessions: HashMap<u128, Addr<A>>, // user_id -> ws_connection(user)
-    pub rooms: HashMap<u128, (HashSet<u128>, Game)>, // room_id -> (set of user_ids, game)
+    pub rooms: HashMap<u128, Room>, // room_id -> room (set of user_ids, moderation)
+    pub codes: HashMap<String, u128>, // join code -> room_id
+    pub games: HashMap<u128, Addr<GameRoom<A>>>, // room_id -> actor running the game of the room
+    pub arbiters: Vec<ArbiterHandle>, // threads the room actors are spread over, empty to run them next to the lobby
+    pub next_arbiter: usize,
+    pub matchmaker: Matchmaker, // quick-play queue
+    pub names: HashMap<u128, String>, // user_id -> player name, only named players are rated
+    pub addresses: HashMap<u128, IpAddr>, // user_id -> address the user connected from
+    pub ratings: Ratings,
+    pub resumable: ResumableSessions, // resume tokens and users whose connection dropped
+    pub latencies: HashMap<u128, Duration>, // user_id -> last measured round trip time
+    pub last_seen: HashMap<u128, Instant>, // user_id -> last message received from the user
+    pub spectators: HashSet<u128>, // users that connected to watch only
+    pub room_config: RoomConfig, // settings every room is opened with
+    pub shutdown: Option<Instant>, // deadline for the running rounds once the server is shutting down
+    pub drained: Option<oneshot::Sender<()>>, // told once every connection was closed on shutdown
 }
 
+/// Room that always exists, quick-play users wait in it
+pub const DEFAULT_ROOM: u128 = 0;
+
+/// How long the lobby and the rooms may take to answer a readiness check
+pub const READY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A room whose game wasn't updated for this long is considered stuck
+const STALLED_AFTER: Duration = Duration::from_secs(1);
+
+/// Remaining seconds of the shutdown at which the users get reminded
+const SHUTDOWN_COUNTDOWN: [u64; 8] = [30, 20, 10, 5, 4, 3, 2, 1];
+
 impl<A> Default for Lobby<A>
 where
-    A: Actor + Handler<WsMessage>,
-    <A as Actor>::Context: ToEnvelope<A, WsMessage>,
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
 {
     fn default() -> Self {
-        let Lobby = Lobby {
+        let lobby = Lobby {
             sessions: HashMap::new(),
-            rooms: HashMap::from([(0, (HashSet::new(), Game::new()))]),
+            rooms: HashMap::new(),
+            codes: HashMap::new(),
+            games: HashMap::new(),
+            arbiters: Vec::new(),
+            next_arbiter: 0,
+            matchmaker: Matchmaker::default(),
+            names: HashMap::new(),
+            addresses: HashMap::new(),
+            ratings: Ratings::load("ratings.json"),
+            resumable: ResumableSessions::default(),
+            latencies: HashMap::new(),
+            last_seen: HashMap::new(),
+            spectators: HashSet::new(),
+            room_config: RoomConfig::default(),
+            shutdown: None,
+            drained: None,
         };
         // Lobby.sessions.iter().map(|(k, v)| v.send(msg))
-        Lobby
+        lobby
     }
 }
 
 /// Implement Lobby as Aactor interface
 impl<A> Actor for Lobby<A>
 where
-    A: Actor + Handler<WsMessage>,
-    <A as Actor>::Context: ToEnvelope<A, WsMessage>,
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
 {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        self.spawn_game_update_job(ctx);
+        self.open_room(DEFAULT_ROOM, Room::waiting(), ctx);
+        self.spawn_matchmaking_job(ctx);
+        self.spawn_session_expiry_job(ctx);
     }
 }
 
 /// Implement specific functionality for Lobby
 impl<A> Lobby<A>
 where
-    A: Actor + Handler<WsMessage>,
-    <A as Actor>::Context: ToEnvelope<A, WsMessage>,
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
 {
     /// Send WsMessage to actor with user_id `id_to`
-    fn send_message(&self, message: &str, id_to: &u128) -> Result<(), String> {
+    fn send_message(&self, message: &str, id_to: &u128) -> Result<(), LobbyError> {
         // Try to find the actor with the given user_id
-        if let Some(addr) = self.sessions.get(id_to) {
-            return addr
-                .try_send(WsMessage(message.to_string()))
-                .map_err(|err| err.to_string()); // if found we send it the message
-                                                 // Ok(())
+        let addr = self
+            .sessions
+            .get(id_to)
+            .ok_or(LobbyError::UserNotFound(*id_to))?;
+        if !addr.connected() {
+            METRICS.mailbox_failures.with_label_values(&["client"]).inc();
+        }
+        addr.do_send(WsMessage(message.to_string())); // if found we send it the message, even if the connection is backed up
+        Ok(())
+    }
+
+    /// Send message to every connected user
+    fn announce(&self, message: &str) {
+        self.sessions.keys().for_each(|user_id| {
+            self.send_message(message, user_id).unwrap_or(())
+        });
+    }
+
+    /// Send message to every user in room `room_id`
+    fn broadcast(&self, message: &str, room_id: &u128) {
+        if let Some(room) = self.rooms.get(room_id) {
+            room.users.iter().for_each(|user_id| {
+                self.send_message(message, user_id).unwrap_or(())
+            });
+        }
+    }
+
+    /// Add room `id` to the directory and spin up the actor running its game
+    fn open_room(&mut self, id: u128, mut room: Room, ctx: &mut Context<Self>) {
+        let mut config = self.room_config;
+        config.spectator_delay = room.spectator_delay.unwrap_or(config.spectator_delay);
+        room.code = loop {
+            let code = random_code();
+            if !self.codes.contains_key(&code) {
+                break code;
+            }
+        };
+        self.codes.insert(room.code.clone(), id);
+        info!(room = %id, code = %room.code, "room opened");
+        let game = GameRoom::new(id, ctx.address(), config);
+        let addr = match self.arbiters.get(self.next_arbiter % self.arbiters.len().max(1)) {
+            Some(arbiter) => GameRoom::start_in_arbiter(arbiter, |_| game),
+            None => game.start(),
+        };
+        self.next_arbiter += 1;
+        self.rooms.insert(id, room);
+        self.games.insert(id, addr);
+        self.count_rooms();
+    }
+
+    /// Random id no room is using yet
+    fn new_room_id(&self) -> u128 {
+        loop {
+            let id = rand::random::<u64>() as u128;
+            if !self.rooms.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+
+    /// Update the number of rooms per phase in the metrics
+    fn count_rooms(&self) {
+        let running = self.rooms.values().filter(|room| room.running).count();
+        METRICS.rooms.with_label_values(&["running"]).set(running as i64);
+        METRICS.rooms.with_label_values(&["waiting"]).set((self.rooms.len() - running) as i64);
+    }
+
+    /// Remove room `id` from the directory and stop its actor
+    fn close_room(&mut self, id: u128) {
+        info!(room = %id, "room closed");
+        if let Some(room) = self.rooms.remove(&id) {
+            self.codes.remove(&room.code);
+        }
+        if let Some(addr) = self.games.remove(&id) {
+            addr.do_send(CloseRoom);
+        }
+        self.count_rooms();
+    }
+
+    /// Remove user from the room and the lobby, migrating the host if needed
+    fn remove_session(&mut self, id: u128, room_id: u128) {
+        let suspended = self.resumable.is_suspended(id);
+        if self.sessions.remove(&id).is_none() && !suspended {
+            return;
+        }
+        info!(session = %id, room = %room_id, "session ended");
+        self.resumable.forget(id);
+        self.latencies.remove(&id);
+        self.last_seen.remove(&id);
+        self.spectators.remove(&id);
+        self.matchmaker.remove(id);
+        self.names.remove(&id);
+        self.addresses.remove(&id);
+        self.leave_room(id, room_id, "disconnected.");
+    }
+
+    /// Take user out of room `room_id` letting the others know with `notice`, removing the room once empty
+    fn leave_room(&mut self, id: u128, room_id: u128, notice: &str) {
+        let room = match self.rooms.get_mut(&room_id) {
+            Some(room) => room,
+            None => return,
+        };
+        let new_host = room.leave(id);
+        if let Some(game) = self.games.get(&room_id) {
+            game.do_send(Leave { id });
+        }
+        if room.users.is_empty() && room_id != DEFAULT_ROOM {
+            //nobody left in the room, remove it entirely
+            self.close_room(room_id);
+            return;
+        }
+        self.broadcast(&format!("{} {}", id, notice), &room_id);
+        if let Some(host) = new_host {
+            self.broadcast(&format!("{} is now the host", host), &room_id);
+        }
+    }
+
+    /// Reattach connection `addr` to the session the resume `token` belongs to, returns (user_id, room_id)
+    fn resume_session(&mut self, token: &str, access: &RoomAccess, addr: Addr<A>) -> Result<Joined, LobbyError> {
+        let id = self
+            .resumable
+            .resolve(token)
+            .ok_or(LobbyError::InvalidResumeToken)?;
+        let room_id = match self.resumable.resume(id) {
+            Some(room_id) => room_id,
+            // the old connection is still around, we just didn't notice it died yet
+            None => self.room_of(id).ok_or(LobbyError::SessionExpired)?,
+        };
+        // whoever holds the token may connect from elsewhere than the session they resume
+        if self.rooms.get(&room_id).is_some_and(|room| room.is_banned(id, access)) {
+            self.kick(id, room_id, "Banned by host");
+            return Err(LobbyError::Banned);
+        }
+        if let Some(address) = access.address {
+            self.addresses.insert(id, address);
+        }
+        self.last_seen.insert(id, Instant::now());
+
+        info!(session = %id, room = %room_id, "session resumed");
+        self.broadcast(&format!("{} reconnected.", id), &room_id);
+        if let Some(game) = self.games.get(&room_id) {
+            game.do_send(Attach {
+                id,
+                addr: addr.clone(),
+            });
+        }
+        if let Some(old) = self.sessions.insert(id, addr) {
+            old.do_send(WsClose(Some(CloseReason {
+                code: CloseCode::Policy,
+                description: Some(String::from("Session resumed by another connection")),
+            })));
+        }
+        let token = self.resumable.issue(id);
+        self.send_message(&format!("your id is {}", id), &id)
+            .unwrap_or(());
+        self.send_message(&format!("resume token: {}", token), &id)
+            .unwrap_or(());
+        Ok(Joined {
+            id,
+            room_id,
+            game: self.game_for(id, room_id),
+        })
+    }
+
+    /// Where user `id` sends their inputs in room `room_id`, None for spectators
+    fn game_for(&self, id: u128, room_id: u128) -> Option<Recipient<ClientActorMessage>> {
+        if self.spectators.contains(&id) {
+            return None;
+        }
+        self.games.get(&room_id).map(|game| game.clone().recipient())
+    }
+
+    /// Room user `id` is in
+    fn room_of(&self, id: u128) -> Option<u128> {
+        self.rooms
+            .iter()
+            .find(|(_, room)| room.users.contains(&id))
+            .map(|(room_id, _)| *room_id)
+    }
+
+    /// Move user `id` from room `from` into room `to`, letting their connection know about it
+    fn move_session(&mut self, id: u128, from: u128, to: u128, ctx: &mut Context<Self>) {
+        let addr = match self.sessions.get(&id) {
+            Some(addr) => addr.clone(),
+            None => return,
+        };
+        debug!(session = %id, from = %from, room = %to, "moving session");
+        self.leave_room(id, from, "left the room.");
+        self.broadcast(&format!("{} just joined!", id), &to);
+        if !self.rooms.contains_key(&to) {
+            self.open_room(to, Room::new(None), ctx);
+        }
+        if let Some(room) = self.rooms.get_mut(&to) {
+            room.join(id);
+        }
+        if let Some(game) = self.games.get(&to) {
+            game.do_send(Attach {
+                id,
+                addr: addr.clone(),
+            });
+        }
+        addr.do_send(JoinedRoom {
+            room_id: to,
+            game: self.game_for(id, to),
+        });
+        if let Some(room) = self.rooms.get(&to) {
+            self.send_message(&format!("join code: {}", room.code), &id).unwrap_or(());
+        }
+        if self.rooms.get(&to).is_some_and(|room| room.is_host(id)) {
+            self.send_message("you are the host", &id).unwrap_or(());
+        }
+    }
+
+    /// Group the users waiting for quick-play into partially filled or new rooms
+    fn run_matchmaking(&mut self, ctx: &mut Context<Self>) {
+        // top up quick-play rooms that haven't started yet first
+        let target_size = self.matchmaker.target_size;
+        let open_rooms = self
+            .rooms
+            .iter()
+            .filter(|(_, room)| room.quickplay && !room.locked && !room.running)
+            .filter(|(_, room)| room.users.len() < target_size)
+            .map(|(id, room)| {
+                let rating = room.users.iter().map(|x| self.rating(*x)).sum::<f64>() / room.users.len() as f64;
+                (*id, target_size - room.users.len(), rating)
+            })
+            .collect::<Vec<_>>();
+        for (room_id, space, rating) in open_rooms {
+            for waiting in self.matchmaker.take_near(rating, space, Instant::now()) {
+                self.move_session(waiting.id, waiting.room_id, room_id, ctx);
+            }
+        }
+
+        // then open new rooms for whoever is left
+        while let Some(group) = self.matchmaker.next_group(Instant::now()) {
+            let room_id = self.new_room_id();
+            let mut room = Room::new(None);
+            room.quickplay = true;
+            self.open_room(room_id, room, ctx);
+            for waiting in group {
+                self.move_session(waiting.id, waiting.room_id, room_id, ctx);
+            }
+            if self.rooms.get(&room_id).is_some_and(|room| room.users.is_empty()) {
+                // everyone in the group left before we got to them
+                self.close_room(room_id);
+            }
+        }
+    }
+
+    /// Rating of user `id`, unnamed players are matched with the default rating
+    fn rating(&self, id: u128) -> f64 {
+        self.names
+            .get(&id)
+            .map_or_else(Rating::default, |name| self.ratings.get(name))
+            .rating
+    }
+
+    /// Rate the players of the round that just finished in room `room_id` and tell everyone how it went
+    fn finish_match(&mut self, room_id: u128, placements: Vec<Vec<u128>>) {
+        let named = placements
+            .iter()
+            .map(|ids| ids.iter().filter_map(|id| self.names.get(id).cloned()).collect::<Vec<_>>())
+            .filter(|names| !names.is_empty())
+            .collect::<Vec<_>>();
+        let changes = self.ratings.update(&named);
+        if !changes.is_empty() {
+            self.ratings.save();
+        }
+
+        let standings = placements
+            .iter()
+            .enumerate()
+            .flat_map(|(place, ids)| ids.iter().map(move |id| (place + 1, id)))
+            .map(|(place, id)| {
+                let name = self.names.get(id).cloned().unwrap_or_else(|| id.to_string());
+                match changes.iter().find(|(x, _)| *x == name) {
+                    Some((_, delta)) => format!("{}. {} ({:.0} {:+.0})", place, name, self.ratings.get(&name).rating, delta),
+                    None => format!("{}. {}", place, name),
+                }
+            })
+            .join(", ");
+        self.broadcast(&format!("round over: {}", standings), &room_id);
+    }
+
+    /// Handle a quick-play queue command sent by user `id`
+    fn queue(&mut self, id: u128, room_id: u128, cmd: &str) -> Result<String, LobbyError> {
+        match cmd {
+            "queue" if self.matchmaker.enqueue(id, room_id, self.rating(id)) => {
+                Ok(String::from("Waiting for a quick-play room"))
+            }
+            "queue" => Err(LobbyError::AlreadyQueued),
+            "unqueue" if self.matchmaker.remove(id) => Ok(String::from("Left the quick-play queue")),
+            "unqueue" => Err(LobbyError::NotQueued),
+            _ => Err(LobbyError::UnknownCommand(cmd.to_string())),
+        }
+    }
+
+    /// Close the connection of user `id` and take them out of room `room_id`
+    fn kick(&mut self, id: u128, room_id: u128, reason: &str) {
+        info!(session = %id, room = %room_id, reason, "kicked");
+        if let Some(addr) = self.sessions.get(&id) {
+            addr.do_send(WsClose(Some(CloseReason {
+                code: CloseCode::Policy,
+                description: Some(reason.to_string()),
+            })));
+        }
+        self.remove_session(id, room_id);
+    }
+
+    /// Step the shutdown every second: count down, end the rounds still running at the deadline and
+    /// close every connection once no round is running anymore
+    fn drain(&mut self) {
+        let Some(deadline) = self.shutdown else { return };
+        if self.drained.is_none() {
+            return; // already closed everything
+        }
+        let now = Instant::now();
+        let running = self
+            .rooms
+            .iter()
+            .filter(|(_, room)| room.running)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        // give the rooms a moment to report the results of the rounds we ended
+        if running.is_empty() || now > deadline + Duration::from_secs(2) {
+            self.close_all();
+        } else if now >= deadline {
+            running
+                .iter()
+                .filter_map(|id| self.games.get(id))
+                .for_each(|game| game.do_send(EndRound));
         } else {
-            Err(String::from("User not found"))
+            let left = deadline.duration_since(now).as_secs_f64().ceil() as u64;
+            if SHUTDOWN_COUNTDOWN.contains(&left) {
+                self.announce(&format!("Server shutting down in {} seconds", left));
+            }
+        }
+    }
+
+    /// Close every connection and room for good once the server is shutting down
+    fn close_all(&mut self) {
+        info!(connections = self.sessions.len(), rooms = self.games.len(), "closing everything for shutdown");
+        for addr in self.sessions.values() {
+            addr.do_send(WsClose(Some(CloseReason {
+                code: CloseCode::Away,
+                description: Some(String::from("Server shutting down")),
+            })));
+        }
+        for game in self.games.values() {
+            game.do_send(CloseRoom);
+        }
+        let saved = self.ratings.save();
+        if let Some(drained) = self.drained.take() {
+            // the ratings have to be on disk before the server stops
+            actix::spawn(async move {
+                saved.await.unwrap_or(());
+                drained.send(()).unwrap_or(());
+            });
+        }
+    }
+
+    /// Handle a command changing who can join the room, the answer is only meant for the host
+    fn set_access(&mut self, id: u128, room_id: u128, cmd: &str, arg: Option<&str>) -> Result<String, LobbyError> {
+        if room_id == DEFAULT_ROOM {
+            return Err(LobbyError::WaitingRoom);
+        }
+        let room = self
+            .rooms
+            .get_mut(&room_id)
+            .ok_or(LobbyError::RoomNotFound(room_id))?;
+        if !room.is_host(id) {
+            return Err(LobbyError::NotHost);
+        }
+        match (cmd, arg) {
+            ("visibility", Some(arg)) => {
+                room.visibility = arg.parse()?;
+                if room.visibility != Visibility::Private {
+                    return Ok(format!("Room visibility set to {}", arg));
+                }
+                // nobody could join a private room without a code to hand out
+                let code = match room.invite_code.clone() {
+                    Some(code) => code,
+                    None => room.new_invite_code(),
+                };
+                Ok(format!("Room visibility set to {}, invite code: {}", arg, code))
+            }
+            ("visibility", None) => Err(LobbyError::Usage("!visibility <public|unlisted|private>")),
+            ("password", Some(arg)) => {
+                room.password = Some(arg.to_string());
+                Ok(String::from("Room password set"))
+            }
+            ("password", None) => {
+                room.password = None;
+                Ok(String::from("Room password removed"))
+            }
+            ("invite", _) => Ok(format!("invite code: {}", room.new_invite_code())),
+            _ => Err(LobbyError::UnknownCommand(cmd.to_string())),
+        }
+    }
+
+    /// Handle a moderation command `cmd` with argument `arg` sent by user `id`
+    fn moderate(&mut self, id: u128, room_id: u128, cmd: &str, arg: Option<&str>) -> Result<String, LobbyError> {
+        if room_id == DEFAULT_ROOM {
+            return Err(LobbyError::WaitingRoom);
+        }
+        let room = self
+            .rooms
+            .get_mut(&room_id)
+            .ok_or(LobbyError::RoomNotFound(room_id))?;
+        if !room.is_host(id) {
+            return Err(LobbyError::NotHost);
+        }
+        // parse the target user for commands that need one
+        let target = arg.and_then(|x| x.parse::<u128>().ok());
+        match (cmd, target) {
+            ("delay", _) => {
+                let secs = arg
+                    .and_then(|x| x.parse().ok())
+                    .ok_or(LobbyError::Usage("!delay <seconds>"))?;
+                let delay = Duration::from_secs(secs).min(MAX_SPECTATOR_DELAY);
+                room.spectator_delay = Some(delay);
+                if let Some(game) = self.games.get(&room_id) {
+                    game.do_send(SpectatorDelay(delay));
+                }
+                Ok(format!("Spectators are {} seconds behind", delay.as_secs()))
+            }
+            ("lock", _) => {
+                room.locked = true;
+                Ok(String::from("Room locked"))
+            }
+            ("unlock", _) => {
+                room.locked = false;
+                Ok(String::from("Room unlocked"))
+            }
+            ("start", _) => {
+                if let Some(game) = self.games.get(&room_id) {
+                    game.do_send(StartGame);
+                }
+                Ok(String::from("Game started by host"))
+            }
+            (_, Some(target)) if !room.users.contains(&target) => Err(LobbyError::UserNotInRoom(target)),
+            (_, Some(target)) if target == id => Err(LobbyError::TargetSelf),
+            ("host", Some(target)) => {
+                room.host = Some(target);
+                Ok(format!("{} is now the host", target))
+            }
+            ("ban", Some(target)) => {
+                room.banned.insert(Banned::Id(target));
+                if let Some(name) = self.names.get(&target) {
+                    room.banned.insert(Banned::Name(name.clone()));
+                }
+                if let Some(address) = self.addresses.get(&target) {
+                    room.banned.insert(Banned::Address(*address));
+                }
+                self.kick(target, room_id, "Banned by host");
+                Ok(format!("{} was banned", target))
+            }
+            ("kick", Some(target)) => {
+                self.kick(target, room_id, "Kicked by host");
+                Ok(format!("{} was kicked", target))
+            }
+            ("host", None) => Err(LobbyError::Usage("!host <user_id>")),
+            ("ban", None) => Err(LobbyError::Usage("!ban <user_id>")),
+            ("kick", None) => Err(LobbyError::Usage("!kick <user_id>")),
+            _ => Err(LobbyError::UnknownCommand(cmd.to_string())),
         }
     }
 }
 
 /// Handler for Disconnect message.
-impl<A> Handler<Disconnect> for Lobby<A>
+impl<A> Handler<Disconnect<A>> for Lobby<A>
 where
-    A: Actor + Handler<WsMessage>,
-    <A as Actor>::Context: ToEnvelope<A, WsMessage>,
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
 {
     type Result = ();
 
-    fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
-        if self.sessions.remove(&msg.id).is_some() {
-            self.rooms
-                .get(&msg.room_id)
-                .unwrap()
-                .0
-                .iter()
-                .filter(|conn_id| *conn_id.to_owned() != msg.id)
-                .for_each(|user_id| {
-                    self.send_message(&format!("{} disconnected.", &msg.id), user_id)
-                        .unwrap_or(())
-                });
-            if let Some((Lobby, game)) = self.rooms.get_mut(&msg.room_id) {
-                if Lobby.len() > 1 {
-                    Lobby.remove(&msg.id);
-                } else {
-                    //only one in the Lobby, remove it entirely
-                    self.rooms.remove(&msg.room_id);
-                }
-            }
+    fn handle(&mut self, msg: Disconnect<A>, _: &mut Context<Self>) {
+        // the session might have been resumed by another connection already
+        if self.sessions.get(&msg.id) != Some(&msg.addr) {
+            return;
         }
+        // keep the slot in the room for a while so the user can resume it
+        debug!(session = %msg.id, room = %msg.room_id, "session suspended");
+        self.sessions.remove(&msg.id);
+        self.matchmaker.remove(msg.id);
+        self.resumable.suspend(msg.id, msg.room_id);
+        if let Some(game) = self.games.get(&msg.room_id) {
+            game.do_send(Detach { id: msg.id });
+        }
+        self.broadcast(&format!("{} lost connection.", msg.id), &msg.room_id);
     }
 }
 
 /// Handling connection message
 impl<A> Handler<Connect<A>> for Lobby<A>
 where
-    A: Actor + Handler<WsMessage>,
-    <A as Actor>::Context: ToEnvelope<A, WsMessage>,
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
 {
-    type Result = ();
+    type Result = Result<Joined, LobbyError>;
 
-    fn handle(&mut self, msg: Connect<A>, _: &mut Context<Self>) -> Self::Result {
-        // create a room if necessary, and then add the id to it
-        self.rooms
-            .entry(msg.lobby_id)
-            .or_insert_with(|| (HashSet::new(), Game::new()))
-            .0
-            .insert(msg.self_id);
+    fn handle(&mut self, msg: Connect<A>, ctx: &mut Context<Self>) -> Self::Result {
+        if self.shutdown.is_some() {
+            return Err(LobbyError::ShuttingDown);
+        }
+        if let Some(token) = msg.access.resume.as_deref() {
+            return self.resume_session(token, &msg.access, msg.addr);
+        }
+
+        // names are rated, so one can only be used once at a time and only by its owner
+        let name = msg.access.name.clone().filter(|x| !x.is_empty());
+        if let Some(name) = name.as_deref() {
+            if self.names.values().any(|x| x == name) {
+                return Err(LobbyError::NameTaken(name.to_string()));
+            }
+            self.ratings.verify(name, msg.access.rating_token.as_deref())?;
+        }
 
+        // open a room if asked for a new one, check we are allowed in and then add the id to it
+        let lobby_id = match msg.lobby_id {
+            Some(id) => {
+                let room = self.rooms.get(&id).ok_or(LobbyError::RoomNotFound(id))?;
+                room.can_join(msg.self_id, &msg.access)?;
+                // everyone waiting for quick-play fits in the default room
+                let full = id != DEFAULT_ROOM
+                    && self.room_config.max_users.is_some_and(|max| room.users.len() >= max);
+                if full && !room.users.contains(&msg.self_id) {
+                    return Err(LobbyError::RoomFull);
+                }
+                id
+            }
+            None => {
+                let id = self.new_room_id();
+                self.open_room(id, Room::with_access(&msg.access), ctx);
+                id
+            }
+        };
+        let room = self
+            .rooms
+            .get_mut(&lobby_id)
+            .ok_or(LobbyError::RoomNotFound(lobby_id))?;
+        room.join(msg.self_id);
+        let is_host = room.is_host(msg.self_id);
+        let invite_code = room.invite_code.clone();
+        let join_code = room.code.clone();
+        let rating_token = name.as_deref().and_then(|name| self.ratings.claim(name));
+        if let Some(name) = name {
+            self.names.insert(msg.self_id, name);
+        }
+        if let Some(address) = msg.access.address {
+            self.addresses.insert(msg.self_id, address);
+        }
+        if rating_token.is_some() {
+            self.ratings.save();
+        }
+        if msg.access.role == Role::Spectator {
+            self.spectators.insert(msg.self_id);
+        } else if msg.access.quickplay {
+            self.matchmaker.enqueue(msg.self_id, lobby_id, self.rating(msg.self_id));
+        }
+
+        info!(session = %msg.self_id, room = %lobby_id, "joined room");
         // send to everyone in the room that new uuid just joined
-        self.rooms
-            .get(&msg.lobby_id)
-            .unwrap()
-            .0
-            .iter()
-            .filter(|conn_id| *conn_id.to_owned() != msg.self_id)
-            .for_each(|conn_id| {
-                self.send_message(&format!("{} just joined!", msg.self_id), conn_id)
-                    .unwrap_or(())
-            });
+        self.broadcast(&format!("{} just joined!", msg.self_id), &lobby_id);
 
-        // store the address
+        // store the address and hand it to the game of the room
+        if let Some(game) = self.games.get(&lobby_id) {
+            game.do_send(Attach {
+                id: msg.self_id,
+                addr: msg.addr.clone(),
+            });
+        }
         self.sessions.insert(msg.self_id, msg.addr);
+        self.last_seen.insert(msg.self_id, Instant::now());
 
-        // send self your new uuid
+        // send self your new uuid and how to get back in if the connection drops
         self.send_message(&format!("your id is {}", msg.self_id), &msg.self_id)
             .unwrap_or(());
+        let token = self.resumable.issue(msg.self_id);
+        self.send_message(&format!("resume token: {}", token), &msg.self_id)
+            .unwrap_or(());
+        self.send_message(&format!("join code: {}", join_code), &msg.self_id)
+            .unwrap_or(());
+        if let Some(token) = rating_token {
+            self.send_message(&format!("rating token: {}", token), &msg.self_id)
+                .unwrap_or(());
+        }
+        if is_host {
+            self.send_message("you are the host", &msg.self_id)
+                .unwrap_or(());
+            if let Some(code) = invite_code {
+                self.send_message(&format!("invite code: {}", code), &msg.self_id)
+                    .unwrap_or(());
+            }
+        }
+        Ok(Joined {
+            id: msg.self_id,
+            room_id: lobby_id,
+            game: self.game_for(msg.self_id, lobby_id),
+        })
+    }
+}
+
+/// Handle round trip time measured by a connection
+impl<A> Handler<Latency> for Lobby<A>
+where
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: Latency, _: &mut Context<Self>) -> Self::Result {
+        if !self.sessions.contains_key(&msg.id) {
+            return;
+        }
+        self.latencies.insert(msg.id, msg.rtt);
+        // inputs go straight to the room, so we learn about them from the connection
+        let seen = Instant::now().checked_sub(msg.idle).unwrap_or_else(Instant::now);
+        if self.last_seen.get(&msg.id).is_none_or(|x| *x < seen) {
+            self.last_seen.insert(msg.id, seen);
+        }
+        if let Some(game) = self.games.get(&msg.room_id) {
+            game.do_send(msg);
+        }
+    }
+}
+
+/// Handle listing of the public rooms
+impl<A> Handler<ListRooms> for Lobby<A>
+where
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
+{
+    type Result = MessageResult<ListRooms>;
+
+    fn handle(&mut self, _: ListRooms, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(
+            self.rooms
+                .iter()
+                .filter(|(_, room)| room.visibility == Visibility::Public)
+                .map(|(id, room)| room.info(*id))
+                .collect(),
+        )
+    }
+}
+
+/// Handle looking up the room a join code belongs to
+impl<A> Handler<FindRoom> for Lobby<A>
+where
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
+{
+    type Result = Option<u128>;
+
+    fn handle(&mut self, msg: FindRoom, _: &mut Context<Self>) -> Self::Result {
+        self.codes.get(&msg.0).copied()
     }
 }
 
 /// Handle broadcast message
 impl<A> Handler<ClientActorMessage> for Lobby<A>
 where
-    A: Actor + Handler<WsMessage>,
-    <A as Actor>::Context: ToEnvelope<A, WsMessage>,
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
 {
-    type Result = ();
+    type Result = Result<(), LobbyError>;
 
     fn handle(&mut self, msg: ClientActorMessage, _: &mut Context<Self>) -> Self::Result {
+        if self.sessions.contains_key(&msg.id) {
+            self.last_seen.insert(msg.id, Instant::now());
+        }
+        let _r = self.handle_client_message(&msg);
+        if let Err(err) = &_r {
+            // let the sender know what went wrong
+            self.send_message(&err.to_string(), &msg.id)
+                .unwrap_or(());
+        }
+        _r
+    }
+}
+
+impl<A> Lobby<A>
+where
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
+{
+    fn handle_client_message(&mut self, msg: &ClientActorMessage) -> Result<(), LobbyError> {
         if msg.msg.starts_with("\\w") {
-            if let Some(id_to) = msg.msg.split(' ').collect::<Vec<&str>>().get(1) {
-                if let Ok(id_to) = id_to.parse::<u128>() {
-                    self.send_message(
-                        &msg.msg
-                            .split(' ')
-                            .skip(2)
-                            .fold(String::from(format!("Whisper[{}]", &msg.id)), |_s, x| {
-                                _s + " " + x
-                            }),
-                        &id_to,
-                    )
-                    .unwrap_or(());
-                }
-            }
-            return ()
+            let mut _args = msg.msg.split(' ').skip(1);
+            let id_to = _args
+                .next()
+                .and_then(|x| x.parse::<u128>().ok())
+                .ok_or(LobbyError::Usage("\\w <user_id> <message>"))?;
+            let whisper = _args.fold(format!("Whisper[{}]", &msg.id), |_s, x| _s + " " + x);
+            return self.send_message(&whisper, &id_to);
+        }
+
+        // anything else is meant for the room we are in
+        let room = self
+            .rooms
+            .get(&msg.room_id)
+            .ok_or(LobbyError::RoomNotFound(msg.room_id))?;
+        if !room.users.contains(&msg.id) {
+            // e.g. message meant for a room we already moved the user out of
+            return Err(LobbyError::NotInRoom);
         }
-        if msg.msg.starts_with("!") {
+
+        if let Some(command) = msg.msg.strip_prefix('!') {
             // this means it's a command
-            let _msg = msg.msg[1..].to_string(); // convert to slice -> select -> convert to string
-            if _msg.starts_with("ready") {
-                // start the game
-                if let Some((_user_set, _game)) = self.rooms.get_mut(&msg.room_id) {
-                    // room.1.start();
-                    let _r = _game.ready(msg.id);
-                    self.send_message(format!("ready: {}", _r).as_str(), &msg.id);
+            let mut _args = command.split_whitespace(); // split into command and arguments
+            match _args.next() {
+                // no new rounds or rooms while the server is shutting down
+                Some("ready" | "start" | "queue") if self.shutdown.is_some() => return Err(LobbyError::ShuttingDown),
+                Some("ready" | "queue") if self.spectators.contains(&msg.id) => return Err(LobbyError::Spectating),
+                Some("ready") => self.forward(msg)?,
+                Some(cmd @ ("visibility" | "password" | "invite")) => {
+                    let info = self.set_access(msg.id, msg.room_id, cmd, _args.next())?;
+                    self.send_message(&info, &msg.id)?;
                 }
+                Some(cmd @ ("queue" | "unqueue")) => {
+                    let info = self.queue(msg.id, msg.room_id, cmd)?;
+                    self.send_message(&info, &msg.id)?;
+                }
+                Some(cmd @ ("kick" | "ban" | "host" | "lock" | "unlock" | "start" | "delay")) => {
+                    let info = self.moderate(msg.id, msg.room_id, cmd, _args.next())?;
+                    self.broadcast(&info, &msg.room_id);
+                }
+                Some(cmd) => return Err(LobbyError::UnknownCommand(cmd.to_string())),
+                None => return Err(LobbyError::Usage("!<command> [arguments]")),
             }
-            return ()
-        }
-        // else {
-        //     self.rooms
-        //         .get(&msg.room_id)
-        //         .unwrap()
-        //         .0
-        //         .iter()
-        //         .for_each(|client| self.send_message(&msg.msg, client).unwrap_or(()));
-        // }
-
-        let _input: bool = match msg.msg.parse() {
-            Ok(x) => x,
-            Err(err) => {
-                println!("Input Can't be parsed: {}", err);
-                return ()
-            },
-        };
-        let mut _game = &mut self.rooms.get_mut(&msg.room_id).unwrap().1;
-        let r = match _game.set_input(msg.id, _input) {
-            Some(_) => {
-                // println!("Changed input for player[{}]", msg.id);
-                msg.id
-            }
-            None => {
-                println!("found no player with id[{}]", msg.id);
-                let u = _game.add_player(Player::new(msg.id));
-                // _game.start(); // get fucked! -> TODO: Have message to star game!
-                println!("Game State: {:?}", _game);
-                self.rooms
-                    .get(&msg.room_id)
-                    .unwrap()
-                    .0
-                    .iter()
-                    .for_each(|x| {
-                        self.send_message(
-                            &format!(
-                                "new Bird with id [{}]; joined the game with player_id[{}]!",
-                                u, msg.id
-                            ),
-                            x,
-                        )
-                        .unwrap_or(())
-                    });
-                u
-            }
-        };
+            return Ok(());
+        }
+
+        // inputs are sent to the room by the connection itself, anything else is no command
+        Err(LobbyError::UnknownCommand(msg.msg.clone()))
+    }
+
+    /// Pass a game command of the user on to the room they are in
+    fn forward(&self, msg: &ClientActorMessage) -> Result<(), LobbyError> {
+        let game = self
+            .games
+            .get(&msg.room_id)
+            .ok_or(LobbyError::RoomNotFound(msg.room_id))?;
+        game.try_send(ClientActorMessage {
+            id: msg.id,
+            msg: msg.msg.clone(),
+            room_id: msg.room_id,
+        })
+        .map_err(|err| {
+            METRICS.mailbox_failures.with_label_values(&["room"]).inc();
+            LobbyError::Mailbox(err.to_string())
+        })
     }
 }
 
-trait Client: Actor + Handler<WsMessage> {}
+impl<A> Lobby<A>
+where
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
+{
+    /// Spawn job matching the users waiting for quick-play every second
+    pub fn spawn_matchmaking_job(&self, ctx: &mut Context<Self>) {
+        ctx.run_interval(Duration::from_secs(1), |act, ctx| {
+            if act.shutdown.is_none() {
+                act.run_matchmaking(ctx);
+            }
+        });
+    }
+}
 
 impl<A> Lobby<A>
 where
-    A: Actor + Handler<WsMessage>,
-    <A as Actor>::Context: ToEnvelope<A, WsMessage>,
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
 {
-    pub fn spawn_game_update_job(&self, ctx: &mut Context<Self>) {
-        let _basis = 1_000_000_000;
-        let _frames = 60;
-        let _frame_rate = 1f32 / _frames as f32;
-        // let _frame_rate = 33_333_334;
-        // let _frame_rate = 16_666_667;
-        println!("DEBUG: Spawn game job with interval: {} & dt: {}", _basis / _frames, _frame_rate);
-        ctx.run_interval(Duration::new(0, _basis / _frames), move |act, _ctx| {
-            // println!(
-            //     "T[@{}] updating {} games",
-            //     std::time::SystemTime::now()
-            //         .duration_since(std::time::UNIX_EPOCH)
-            //         .unwrap()
-            //         .as_secs(),
-            //     act.rooms.len()
-            // );
-            act.rooms.values_mut().for_each(|(_, game)| {
-                game.update(_frame_rate);
-            });
-            act.rooms.values().for_each(|(users, _game)| {
-                users.iter().for_each(|user| {
-                    act.send_message(
-                        &format!("{}", serde_json::to_string_pretty(_game).unwrap()),
-                        user,
-                    );
-                });
-            });
+    /// Spawn job removing users that didn't resume their session within the grace period every second
+    pub fn spawn_session_expiry_job(&self, ctx: &mut Context<Self>) {
+        ctx.run_interval(Duration::from_secs(1), |act, _ctx| {
+            for (id, room_id) in act.resumable.expired(Instant::now()) {
+                act.remove_session(id, room_id);
+            }
         });
     }
 }
+
+/// Handle a round starting in one of the rooms
+impl<A> Handler<RoundStarted> for Lobby<A>
+where
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: RoundStarted, _: &mut Context<Self>) {
+        if let Some(room) = self.rooms.get_mut(&msg.room_id) {
+            room.running = true;
+        }
+        self.count_rooms();
+    }
+}
+
+/// Handle a round finishing in one of the rooms
+impl<A> Handler<RoundOver> for Lobby<A>
+where
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: RoundOver, _: &mut Context<Self>) {
+        if let Some(room) = self.rooms.get_mut(&msg.room_id) {
+            room.running = false;
+        }
+        self.count_rooms();
+        self.finish_match(msg.room_id, msg.placements);
+    }
+}
+
+/// Handle a room asking to get rid of one of its users
+impl<A> Handler<Kick> for Lobby<A>
+where
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: Kick, _: &mut Context<Self>) {
+        if !self.rooms.get(&msg.room_id).is_some_and(|room| room.users.contains(&msg.id)) {
+            return;
+        }
+        self.kick(msg.id, msg.room_id, &msg.reason);
+        self.broadcast(&format!("{} was kicked: {}", msg.id, msg.reason), &msg.room_id);
+    }
+}
+
+/// Handle the server shutting down, answered once every connection was closed
+impl<A> Handler<Shutdown> for Lobby<A>
+where
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
+{
+    type Result = ResponseFuture<()>;
+
+    fn handle(&mut self, msg: Shutdown, ctx: &mut Context<Self>) -> Self::Result {
+        let (drained, done) = oneshot::channel();
+        if self.shutdown.is_none() {
+            self.shutdown = Some(Instant::now() + msg.grace);
+            self.drained = Some(drained);
+            self.matchmaker.queue.clear();
+            self.announce(&format!(
+                "Server shutting down in {} seconds, running rounds may finish until then",
+                msg.grace.as_secs()
+            ));
+            if !self.rooms.values().any(|room| room.running) {
+                self.close_all();
+            }
+            ctx.run_interval(Duration::from_secs(1), |act, _ctx| act.drain());
+        }
+        Box::pin(async move { done.await.unwrap_or(()) })
+    }
+}
+
+/// Handle a readiness check, asking every room how long ago its game was updated
+impl<A> Handler<CheckReady> for Lobby<A>
+where
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
+{
+    type Result = ResponseFuture<Readiness>;
+
+    fn handle(&mut self, _: CheckReady, _: &mut Context<Self>) -> Self::Result {
+        let shutting_down = self.shutdown.is_some();
+        // ask all rooms at once, then collect the answers
+        let checks = self
+            .games
+            .iter()
+            .map(|(id, game)| (*id, game.send(LastTick).timeout(READY_TIMEOUT)))
+            .collect::<Vec<_>>();
+        Box::pin(async move {
+            let mut stalled = Vec::new();
+            for (id, check) in checks {
+                if !check.await.is_ok_and(|age| age <= STALLED_AFTER) {
+                    stalled.push(id);
+                }
+            }
+            Readiness { shutting_down, stalled }
+        })
+    }
+}
+
+/// Handle an admin listing the sessions
+impl<A> Handler<ListSessions> for Lobby<A>
+where
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
+{
+    type Result = MessageResult<ListSessions>;
+
+    fn handle(&mut self, _: ListSessions, _: &mut Context<Self>) -> Self::Result {
+        let now = Instant::now();
+        let connected = self
+            .sessions
+            .keys()
+            .map(|id| (*id, true, self.last_seen.get(id).copied().unwrap_or(now)));
+        let suspended = self
+            .resumable
+            .suspended
+            .iter()
+            .map(|(id, (_, since))| (*id, false, *since));
+        MessageResult(
+            connected
+                .chain(suspended)
+                .sorted_by_key(|(id, _, _)| *id)
+                .map(|(id, connected, since)| SessionInfo {
+                    id: id.to_string(),
+                    room: self.room_of(id).map(|x| x.to_string()),
+                    name: self.names.get(&id).cloned(),
+                    connected,
+                    rtt_ms: self.latencies.get(&id).map(|x| x.as_millis() as u64),
+                    idle_secs: now.duration_since(since).as_secs(),
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Handle an admin disconnecting a user, their session can't be resumed
+impl<A> Handler<ForceDisconnect> for Lobby<A>
+where
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
+{
+    type Result = Result<(), LobbyError>;
+
+    fn handle(&mut self, msg: ForceDisconnect, _: &mut Context<Self>) -> Self::Result {
+        let room_id = self.room_of(msg.id).ok_or(LobbyError::UserNotFound(msg.id))?;
+        self.kick(msg.id, room_id, "Disconnected by the server");
+        self.broadcast(&format!("{} was disconnected by the server", msg.id), &room_id);
+        Ok(())
+    }
+}
+
+/// Handle an admin closing a room, everyone in it gets disconnected
+impl<A> Handler<ForceCloseRoom> for Lobby<A>
+where
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
+{
+    type Result = Result<(), LobbyError>;
+
+    fn handle(&mut self, msg: ForceCloseRoom, _: &mut Context<Self>) -> Self::Result {
+        let room = self
+            .rooms
+            .get(&msg.room_id)
+            .ok_or(LobbyError::RoomNotFound(msg.room_id))?;
+        // the room closes itself once the last user left
+        for id in room.users.iter().copied().collect::<Vec<_>>() {
+            self.kick(id, msg.room_id, "Room closed by the server");
+        }
+        if self.rooms.contains_key(&msg.room_id) && msg.room_id != DEFAULT_ROOM {
+            self.close_room(msg.room_id);
+        }
+        Ok(())
+    }
+}
+
+/// Handle an admin aborting the round of a room
+impl<A> Handler<ResetRoom> for Lobby<A>
+where
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
+{
+    type Result = Result<(), LobbyError>;
+
+    fn handle(&mut self, msg: ResetRoom, _: &mut Context<Self>) -> Self::Result {
+        let room = self
+            .rooms
+            .get_mut(&msg.room_id)
+            .ok_or(LobbyError::RoomNotFound(msg.room_id))?;
+        // the room doesn't report a reset round as over
+        room.running = false;
+        if let Some(game) = self.games.get(&msg.room_id) {
+            game.do_send(ResetRoom { room_id: msg.room_id });
+        }
+        self.count_rooms();
+        self.broadcast("The round was reset by the server, ready up again", &msg.room_id);
+        Ok(())
+    }
+}
+
+/// Handle an admin changing the rules of a room, refused while a round is running
+impl<A> Handler<SetGameConfig> for Lobby<A>
+where
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
+{
+    type Result = Result<(), LobbyError>;
+
+    fn handle(&mut self, msg: SetGameConfig, _: &mut Context<Self>) -> Self::Result {
+        let room = self
+            .rooms
+            .get(&msg.room_id)
+            .ok_or(LobbyError::RoomNotFound(msg.room_id))?;
+        if room.running {
+            return Err(LobbyError::RoundRunning);
+        }
+        if let Some(game) = self.games.get(&msg.room_id) {
+            game.do_send(msg);
+        }
+        Ok(())
+    }
+}
+
+/// Handle an admin announcing something to every connected user
+impl<A> Handler<Announce> for Lobby<A>
+where
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: Announce, _: &mut Context<Self>) {
+        info!(message = %msg.0, "announcement");
+        self.announce(&format!("Server: {}", msg.0));
+    }
+}
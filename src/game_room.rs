@@ -0,0 +1,642 @@
+use actix::{dev::ToEnvelope, Actor, ActorContext, Addr, AsyncContext, Context, Handler, MessageResult};
+use actix::prelude::SendError;
+use bytestring::ByteString;
+use tracing::{debug, info, info_span, warn, Span};
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::LobbyError,
+    game::{
+        game::{Game, GameConfig},
+        input::{Input, InputError},
+        player::Player,
+        view::Snapshots,
+    },
+    game_lobby::Lobby,
+    messages::*,
+    metrics::METRICS,
+};
+
+/// Longest a room can hold back the game from its spectators
+pub const MAX_SPECTATOR_DELAY: Duration = Duration::from_secs(60);
+
+/// Settings every room is opened with
+#[derive(Debug, Clone, Copy)]
+pub struct RoomConfig {
+    pub tick_rate: u32, // game updates and snapshots per second
+    pub idle_timeout: Duration, // players alive in a running game get kicked after idling this long
+    pub slow_timeout: Duration, // connections that can't keep up with the snapshots for this long get kicked
+    pub spectator_delay: Duration, // how far behind the game spectators are, so they can't relay it to players
+    pub max_users: Option<usize>, // users that fit in the room, unlimited if not set
+    pub game: GameConfig, // rules the game starts with, changes apply from the next round
+}
+
+impl Default for RoomConfig {
+    fn default() -> Self {
+        RoomConfig {
+            tick_rate: 60,
+            idle_timeout: Duration::from_secs(60),
+            slow_timeout: Duration::from_secs(5),
+            spectator_delay: Duration::ZERO,
+            max_users: None,
+            game: GameConfig::default(),
+        }
+    }
+}
+
+/// Snapshots held back from the spectators of a room
+#[derive(Debug)]
+pub struct DelayedStream {
+    pub delay: Duration,
+    buffer: VecDeque<(Instant, ByteString)>, // oldest first
+}
+
+impl DelayedStream {
+    pub fn new(delay: Duration) -> Self {
+        DelayedStream {
+            delay,
+            buffer: VecDeque::new(),
+        }
+    }
+
+    /// Buffer the snapshot taken at `now`, returns the newest one that is old enough to be sent if any
+    pub fn push(&mut self, snapshot: ByteString, now: Instant) -> Option<ByteString> {
+        if self.delay.is_zero() {
+            self.buffer.clear();
+            return Some(snapshot);
+        }
+        self.buffer.push_back((now, snapshot));
+        let mut due = None;
+        while self.buffer.front().is_some_and(|(at, _)| now.duration_since(*at) >= self.delay) {
+            due = self.buffer.pop_front().map(|(_, x)| x);
+        }
+        due
+    }
+}
+
+/// When the users of a room were last active, to kick the ones idling in a running game
+#[derive(Debug, Default)]
+pub struct Activity {
+    last: HashMap<u128, Instant>, // user_id -> last message received from the user, or the start of the round
+}
+
+impl Activity {
+    pub fn seen(&mut self, id: u128, now: Instant) {
+        self.last.insert(id, now);
+    }
+
+    pub fn forget(&mut self, id: u128) {
+        self.last.remove(&id);
+    }
+
+    /// Everyone starts the round active, waiting for the others to ready up isn't idling
+    pub fn round_started(&mut self, now: Instant) {
+        self.last.values_mut().for_each(|x| *x = now);
+    }
+
+    /// Users that weren't active for longer than `timeout`
+    pub fn idle(&self, now: Instant, timeout: Duration) -> impl Iterator<Item = u128> + '_ {
+        self.last
+            .iter()
+            .filter(move |(_, x)| now.duration_since(**x) > timeout)
+            .map(|(id, _)| *id)
+    }
+}
+
+/// Actor running the game of a single room, the lobby routes the inputs of the room's users to it
+pub struct GameRoom<A>
+where
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
+{
+    pub id: u128,
+    pub game: Game,
+    pub lobby: Addr<Lobby<A>>,
+    pub sessions: HashMap<u128, Addr<A>>, // user_id -> ws_connection(user), only users currently connected
+    pub last_active: Activity,
+    pub saturated: HashMap<u128, Instant>, // user_id -> since when their connection doesn't take any snapshots
+    pub spectators: DelayedStream, // public snapshots on their way to the users not playing
+    pub config: RoomConfig,
+    pub span: Span, // carries the room id to the log events of the room
+    pub last_tick: Instant,
+}
+
+impl<A> GameRoom<A>
+where
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
+{
+    pub fn new(id: u128, lobby: Addr<Lobby<A>>, config: RoomConfig) -> Self {
+        GameRoom {
+            id,
+            game: Game::new(config.game),
+            lobby,
+            sessions: HashMap::new(),
+            last_active: Activity::default(),
+            saturated: HashMap::new(),
+            spectators: DelayedStream::new(config.spectator_delay),
+            config,
+            span: info_span!("room", room = %id),
+            last_tick: Instant::now(),
+        }
+    }
+
+    /// Send WsMessage to the connection of user `id_to`, it is queued even if the connection is backed up
+    fn send_message(&self, message: &str, id_to: &u128) -> Result<(), LobbyError> {
+        let addr = self
+            .sessions
+            .get(id_to)
+            .ok_or(LobbyError::UserNotFound(*id_to))?;
+        if !addr.connected() {
+            METRICS.mailbox_failures.with_label_values(&["client"]).inc();
+        }
+        addr.do_send(WsMessage(message.to_string()));
+        Ok(())
+    }
+
+    /// Offer every connection its snapshot of the game, kicking the ones that didn't take any for too long
+    fn send_snapshots(&mut self) {
+        let snapshots = Snapshots::new(&self.game);
+        let now = Instant::now();
+        // players get the live game, spectators the one that is delayed long enough
+        let delayed = self.spectators.push(snapshots.public(), now);
+        let mut slow = Vec::new();
+        for (id, addr) in self.sessions.iter() {
+            let snapshot = match (self.game.players().contains_key(id), &delayed) {
+                (true, _) => Snapshot {
+                    public: snapshots.public(),
+                    own: snapshots.own(*id),
+                },
+                (false, Some(x)) => Snapshot {
+                    public: x.clone(),
+                    own: None,
+                },
+                (false, None) => continue,
+            };
+            let bytes = (snapshot.public.len() + snapshot.own.as_ref().map_or(0, |x| x.len())) as u64;
+            match addr.try_send(snapshot) {
+                // the connection isn't writing to its client fast enough, this snapshot is simply skipped
+                Err(SendError::Full(_)) => {
+                    METRICS.snapshots_skipped.inc();
+                    let since = *self.saturated.entry(*id).or_insert(now);
+                    if now.duration_since(since) > self.config.slow_timeout {
+                        slow.push(*id);
+                    }
+                }
+                Err(SendError::Closed(_)) => {
+                    METRICS.mailbox_failures.with_label_values(&["client"]).inc();
+                }
+                Ok(()) => {
+                    METRICS.snapshot_bytes.inc_by(bytes);
+                    self.saturated.remove(id);
+                }
+            }
+        }
+        for id in slow {
+            warn!(parent: &self.span, player = %id, "connection can't keep up with the snapshots, kicking");
+            self.sessions.remove(&id);
+            self.saturated.remove(&id);
+            self.lobby.do_send(Kick {
+                room_id: self.id,
+                id,
+                reason: String::from("Connection too slow"),
+            });
+        }
+    }
+
+    /// Send message to every connected user of the room
+    fn broadcast(&self, message: &str) {
+        self.sessions.keys().for_each(|user_id| {
+            self.send_message(message, user_id).unwrap_or(())
+        });
+    }
+
+    /// Let the lobby know if the game just started
+    fn check_started(&mut self, was_running: bool) {
+        if !was_running && self.game.running {
+            info!(parent: &self.span, players = self.game.players().len(), "round started");
+            self.last_active.round_started(Instant::now());
+            self.lobby.do_send(RoundStarted { room_id: self.id });
+        }
+    }
+
+    fn handle_client_message(&mut self, msg: &ClientActorMessage) -> Result<(), LobbyError> {
+        // the lobby only forwards game commands and inputs
+        if let Some(command) = msg.msg.strip_prefix('!') {
+            return match command.split_whitespace().next() {
+                Some("ready") => {
+                    let was_running = self.game.running;
+                    let _r = self.game.ready(msg.id);
+                    self.check_started(was_running);
+                    self.send_message(format!("ready: {}", _r).as_str(), &msg.id)
+                }
+                Some(cmd) => Err(LobbyError::UnknownCommand(cmd.to_string())),
+                None => Err(LobbyError::Usage("!<command> [arguments]")),
+            };
+        }
+
+        let _input: Input = msg.msg.parse()?;
+        match self.game.set_input(msg.id, _input) {
+            Ok(()) => Ok(()),
+            Err(InputError::NotPlaying) => {
+                let u = self.game.add_player(Player::new(msg.id));
+                info!("joined the game");
+                self.broadcast(&format!(
+                    "new Bird with id [{}]; joined the game with player_id[{}]!",
+                    u, msg.id
+                ));
+                Ok(())
+            }
+            Err(e) => {
+                if e.is_suspicious() {
+                    warn!(reason = %e, so_far = self.game.suspicious(msg.id).map_or(0, |x| x.count), "suspicious input");
+                }
+                Err(LobbyError::InputRejected(e))
+            }
+        }
+    }
+
+    pub fn spawn_game_update_job(&self, ctx: &mut Context<Self>) {
+        let _basis = 1_000_000_000;
+        let _frames = self.config.tick_rate;
+        let _frame_rate = 1f32 / _frames as f32;
+        debug!(parent: &self.span, tick_rate = _frames, dt = _frame_rate, "game loop started");
+        let players = METRICS.room_players.with_label_values(&[&self.id.to_string()]);
+        ctx.run_interval(Duration::new(0, _basis / _frames), move |act, _ctx| {
+            let _timer = METRICS.tick_duration.start_timer();
+            act.last_tick = Instant::now();
+            act.game.update(_frame_rate);
+            if let Some(placements) = act.game.take_results() {
+                info!(parent: &act.span, ?placements, "round over");
+                act.game.config = act.config.game;
+                act.lobby.do_send(RoundOver {
+                    room_id: act.id,
+                    placements,
+                });
+            }
+            act.send_snapshots();
+            players.set(act.game.players().len() as i64);
+        });
+    }
+
+    /// Spawn job kicking players that idle while their bird is alive in a running game every second
+    pub fn spawn_idle_job(&self, ctx: &mut Context<Self>) {
+        ctx.run_interval(Duration::from_secs(1), |act, _ctx| {
+            if !act.game.running {
+                return;
+            }
+            let now = Instant::now();
+            act.last_active
+                .idle(now, act.config.idle_timeout)
+                .filter(|id| act.game.is_alive(*id))
+                .for_each(|id| {
+                    info!(parent: &act.span, player = %id, "idle for too long, kicking");
+                    act.lobby.do_send(Kick {
+                        room_id: act.id,
+                        id,
+                        reason: String::from("Idle for too long"),
+                    });
+                });
+        });
+    }
+}
+
+impl<A> Actor for GameRoom<A>
+where
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
+{
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.spawn_game_update_job(ctx);
+        self.spawn_idle_job(ctx);
+    }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        METRICS
+            .room_players
+            .remove_label_values(&[&self.id.to_string()])
+            .unwrap_or(());
+    }
+}
+
+/// Handle a user joining the room or reattaching their session to it
+impl<A> Handler<Attach<A>> for GameRoom<A>
+where
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: Attach<A>, _: &mut Context<Self>) {
+        self.sessions.insert(msg.id, msg.addr);
+        self.last_active.seen(msg.id, Instant::now());
+    }
+}
+
+/// Handle the connection of a user dropping, their bird stays in the game
+impl<A> Handler<Detach> for GameRoom<A>
+where
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: Detach, _: &mut Context<Self>) {
+        self.sessions.remove(&msg.id);
+        self.saturated.remove(&msg.id);
+    }
+}
+
+/// Handle a user leaving the room for good
+impl<A> Handler<Leave> for GameRoom<A>
+where
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: Leave, _: &mut Context<Self>) {
+        self.sessions.remove(&msg.id);
+        self.saturated.remove(&msg.id);
+        self.last_active.forget(msg.id);
+        self.game.remove_player(msg.id);
+    }
+}
+
+/// Handle the host starting the game
+impl<A> Handler<StartGame> for GameRoom<A>
+where
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
+{
+    type Result = ();
+
+    fn handle(&mut self, _: StartGame, _: &mut Context<Self>) {
+        let was_running = self.game.running;
+        self.game.start();
+        self.check_started(was_running);
+    }
+}
+
+/// Handle the lobby ending the round, the results are reported with the next update
+impl<A> Handler<EndRound> for GameRoom<A>
+where
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
+{
+    type Result = ();
+
+    fn handle(&mut self, _: EndRound, _: &mut Context<Self>) {
+        self.game.end_round();
+    }
+}
+
+/// Handle the room being closed by the lobby
+impl<A> Handler<CloseRoom> for GameRoom<A>
+where
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
+{
+    type Result = ();
+
+    fn handle(&mut self, _: CloseRoom, ctx: &mut Context<Self>) {
+        ctx.stop();
+    }
+}
+
+/// Handle the host changing the spectator delay of the room
+impl<A> Handler<SpectatorDelay> for GameRoom<A>
+where
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: SpectatorDelay, _: &mut Context<Self>) {
+        // snapshots already buffered are sent once they are old enough for the new delay
+        self.spectators.delay = msg.0;
+    }
+}
+
+/// Handle the round being reset by an admin, nobody gets placed
+impl<A> Handler<ResetRoom> for GameRoom<A>
+where
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
+{
+    type Result = Result<(), LobbyError>;
+
+    fn handle(&mut self, _: ResetRoom, _: &mut Context<Self>) -> Self::Result {
+        info!(parent: &self.span, "round reset");
+        self.game.reset();
+        self.game.config = self.config.game;
+        Ok(())
+    }
+}
+
+/// Handle an admin changing the rules of the room, a running round keeps its rules
+impl<A> Handler<SetGameConfig> for GameRoom<A>
+where
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
+{
+    type Result = Result<(), LobbyError>;
+
+    fn handle(&mut self, msg: SetGameConfig, _: &mut Context<Self>) -> Self::Result {
+        info!(parent: &self.span, config = ?msg.config, "game config changed");
+        self.config.game = msg.config;
+        if !self.game.running {
+            self.game.config = msg.config;
+        }
+        Ok(())
+    }
+}
+
+/// Handle the lobby checking the game loop of the room is still running
+impl<A> Handler<LastTick> for GameRoom<A>
+where
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
+{
+    type Result = MessageResult<LastTick>;
+
+    fn handle(&mut self, _: LastTick, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.last_tick.elapsed())
+    }
+}
+
+/// Handle round trip time measured by the connection of a user
+impl<A> Handler<Latency> for GameRoom<A>
+where
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: Latency, _: &mut Context<Self>) {
+        // players see everyones ping in the game snapshot
+        self.game.set_ping(msg.id, msg.rtt.as_millis() as u32);
+    }
+}
+
+/// Handle inputs sent by the connections of the room's users and game commands forwarded by the lobby
+impl<A> Handler<ClientActorMessage> for GameRoom<A>
+where
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+    <A as Actor>::Context: ToEnvelope<A, WsMessage>
+        + ToEnvelope<A, WsClose>
+        + ToEnvelope<A, JoinedRoom>
+        + ToEnvelope<A, Snapshot>,
+{
+    type Result = Result<(), LobbyError>;
+
+    fn handle(&mut self, msg: ClientActorMessage, _: &mut Context<Self>) -> Self::Result {
+        // e.g. input sent before the connection learned it was moved into another room
+        if !self.sessions.contains_key(&msg.id) {
+            return Err(LobbyError::NotInRoom);
+        }
+        self.last_active.seen(msg.id, Instant::now());
+        let _span = info_span!(parent: &self.span, "player", player = %msg.id).entered();
+        let _r = self.handle_client_message(&msg);
+        if let Err(err) = &_r {
+            debug!(error = %err, "message rejected");
+            // let the sender know what went wrong
+            self.send_message(&err.to_string(), &msg.id)
+                .unwrap_or(());
+        }
+        _r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn waiting_for_the_round_to_start_is_not_idling() {
+        let start = Instant::now();
+        let mut activity = Activity::default();
+        activity.seen(1, start);
+        activity.seen(2, start + Duration::from_secs(90));
+        let round = start + Duration::from_secs(90);
+        activity.round_started(round);
+        assert_eq!(activity.idle(round + Duration::from_secs(1), TIMEOUT).count(), 0);
+        let mut idle = activity.idle(round + TIMEOUT + Duration::from_secs(1), TIMEOUT).collect::<Vec<_>>();
+        idle.sort();
+        assert_eq!(idle, vec![1, 2]);
+    }
+
+    #[test]
+    fn only_users_without_messages_are_idle() {
+        let start = Instant::now();
+        let mut activity = Activity::default();
+        activity.seen(1, start);
+        activity.seen(2, start);
+        activity.seen(3, start);
+        activity.seen(2, start + Duration::from_secs(30));
+        activity.forget(3);
+        let now = start + Duration::from_secs(61);
+        assert_eq!(activity.idle(now, TIMEOUT).collect::<Vec<_>>(), vec![1]);
+    }
+
+    fn at(start: Instant, secs: u64) -> Instant {
+        start + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn without_delay_snapshots_pass_straight_through() {
+        let start = Instant::now();
+        let mut stream = DelayedStream::new(Duration::ZERO);
+        assert_eq!(stream.push("a".into(), start), Some("a".into()));
+        assert_eq!(stream.push("b".into(), start), Some("b".into()));
+        assert!(stream.buffer.is_empty());
+    }
+
+    #[test]
+    fn snapshots_are_held_back_for_the_delay() {
+        let start = Instant::now();
+        let mut stream = DelayedStream::new(Duration::from_secs(2));
+        assert_eq!(stream.push("a".into(), at(start, 0)), None);
+        assert_eq!(stream.push("b".into(), at(start, 1)), None);
+        assert_eq!(stream.push("c".into(), at(start, 2)), Some("a".into()));
+        assert_eq!(stream.push("d".into(), at(start, 3)), Some("b".into()));
+        assert_eq!(stream.buffer.len(), 2);
+    }
+
+    #[test]
+    fn only_the_newest_of_several_due_snapshots_is_sent() {
+        let start = Instant::now();
+        let mut stream = DelayedStream::new(Duration::from_secs(2));
+        stream.push("a".into(), at(start, 0));
+        stream.push("b".into(), at(start, 1));
+        // a gap in the ticks makes both due at once
+        assert_eq!(stream.push("c".into(), at(start, 5)), Some("b".into()));
+        assert_eq!(stream.buffer.len(), 1);
+    }
+
+    #[test]
+    fn changing_the_delay_applies_to_the_buffered_snapshots() {
+        let start = Instant::now();
+        let mut stream = DelayedStream::new(Duration::from_secs(10));
+        stream.push("a".into(), at(start, 0));
+        stream.push("b".into(), at(start, 1));
+        stream.push("c".into(), at(start, 2));
+        stream.delay = Duration::from_secs(1);
+        assert_eq!(stream.push("d".into(), at(start, 3)), Some("c".into()));
+        assert_eq!(stream.buffer.len(), 1);
+        stream.delay = Duration::from_secs(5);
+        assert_eq!(stream.push("e".into(), at(start, 4)), None);
+        stream.delay = Duration::ZERO;
+        assert_eq!(stream.push("f".into(), at(start, 5)), Some("f".into()));
+        assert!(stream.buffer.is_empty());
+    }
+}
@@ -1,41 +1,147 @@
 mod game_lobby;
-use std::sync::Arc;
+mod game_room;
 
 use actix_files::{Files, NamedFile};
 // use lobby::Lobby;
 use game_lobby::Lobby;
+mod admin;
+mod config;
 mod endpoints;
+mod error;
 mod game;
+mod matchmaking;
 mod messages;
+mod metrics;
+mod rate_limit;
+mod rating;
+mod room;
+mod session;
 mod socket;
-use actix::Actor;
-use actix_web::{middleware::Logger, web::{Data, self}, App, HttpServer, Responder};
+mod tls;
+use actix::{Actor, Arbiter};
+use actix_web::{middleware::Logger, web::{Data, self}, App, HttpServer};
 
-use crate::socket::WsConn;
-use endpoints::start_connection as start_connection_route;
+use crate::{
+    config::{Config, ConfigError, LogFormat, ServerConfig},
+    messages::Shutdown,
+    socket::WsConn,
+};
+use admin::{announce, close_room, disconnect, list_sessions, reset_room, set_game_config};
+use endpoints::{
+    healthz, list_rooms, metrics, readyz, request_line, start_connection as start_connection_route, start_quickplay,
+};
+use std::{io::IsTerminal, time::Duration};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::info;
+use tracing_subscriber::EnvFilter;
 
+async fn index(config: Data<Config>) -> std::io::Result<NamedFile> {
+    NamedFile::open_async(config.server.static_dir.join("index.html")).await
+}
+
+fn exit_invalid(e: ConfigError) -> ! {
+    eprintln!("Invalid configuration: {}", e);
+    std::process::exit(2);
+}
 
-async fn index() -> impl Responder {
-    NamedFile::open_async("./static/index.html").await.unwrap()
+/// Install the subscriber writing the logs of the whole server, including those of actix
+fn init_logging(config: &ServerConfig) {
+    let logs = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::new(&config.log))
+        .with_ansi(std::io::stdout().is_terminal());
+    match config.log_format {
+        LogFormat::Text => logs.init(),
+        LogFormat::Json => logs.json().init(),
+    }
+}
+
+/// Wait for SIGTERM or Ctrl-C
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("can't listen for SIGTERM");
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("debug"));
+    let config = Config::load().unwrap_or_else(|e| exit_invalid(e));
+    let tls = config.tls().unwrap_or_else(|e| exit_invalid(e));
+    init_logging(&config.server);
+
+    // rooms run on their own threads, 0 keeps them on the thread of the lobby
+    let room_threads = config
+        .server
+        .room_threads
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |x| x.get()));
+    let mut lobby = Lobby::<WsConn> {
+        room_config: config.room_config(),
+        arbiters: (0..room_threads).map(|_| Arbiter::new().handle()).collect(),
+        ..Default::default()
+    };
+    if let Some(max) = config.rooms.max_users {
+        lobby.matchmaker.target_size = lobby.matchmaker.target_size.min(max);
+    }
 
-    let chat_server = Data::new(Lobby::<WsConn>::default().start()); //create and spin up a lobby
+    let lobby = lobby.start(); //create and spin up a lobby
+    let chat_server = Data::new(lobby.clone());
+    let heartbeat = Data::new(config.heartbeat());
+    let limits = Data::new(config.rate_limits());
+    let static_dir = config.server.static_dir.clone();
+    let address = (config.server.bind.clone(), config.server.port);
+    let grace = Duration::from_secs(config.server.shutdown_grace);
+    let config = Data::new(config);
 
     let server = HttpServer::new(move || {
         App::new()
-            .service(web::resource("/{group_id}").to(start_connection_route)) //. rename with "as" import or naming conflict
+            .service(web::resource("/rooms").to(list_rooms))
+            .service(web::resource("/metrics").to(metrics))
+            .service(web::resource("/healthz").to(healthz))
+            .service(web::resource("/readyz").to(readyz))
+            .service(
+                web::scope("/admin")
+                    .route("/sessions", web::get().to(list_sessions))
+                    .route("/sessions/{id}", web::delete().to(disconnect))
+                    .route("/rooms/{id}", web::delete().to(close_room))
+                    .route("/rooms/{id}/reset", web::post().to(reset_room))
+                    .route("/rooms/{id}/game", web::put().to(set_game_config))
+                    .route("/announce", web::post().to(announce)),
+            )
+            .service(web::resource("/ws/quickplay").to(start_quickplay))
+            .service(web::resource("/ws/rooms/{room_id}").to(start_connection_route)) //. rename with "as" import or naming conflict
             .app_data(chat_server.clone()) //register the lobby
+            .app_data(heartbeat.clone())
+            .app_data(limits.clone())
+            .app_data(config.clone())
             .service(web::resource("/").to(index)) // serve the index function as the default root
-            .service(Files::new("/static", "./static")) // serve the files in /static folder
-            .wrap(Logger::default())
-    })
-    .bind("127.0.0.1:8080")?
+            .service(Files::new("/static", &static_dir)) // serve the files in the static folder
+            // the default format, only with credentials masked in the query string
+            .wrap(
+                Logger::new(r#"%a "%{request}xi" %s %b "%{Referer}i" "%{User-Agent}i" %T"#)
+                    .custom_request_replace("request", request_line),
+            )
+    });
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    let server = match tls {
+        Some(tls) => server.bind_rustls(&address, tls)?,
+        None => server.bind(&address)?,
+    }
+    .disable_signals() // we shut down ourselves once the rooms are done
     .run();
 
-    println!("server running on http://localhost:8080");
+    let handle = server.handle();
+    actix_web::rt::spawn(async move {
+        shutdown_signal().await;
+        info!(grace = grace.as_secs(), "shutting down, running rounds may finish");
+        // a second signal doesn't wait for the rounds
+        let graceful = tokio::select! {
+            _ = lobby.send(Shutdown { grace }) => true,
+            _ = shutdown_signal() => false,
+        };
+        handle.stop(graceful).await;
+    });
+
+    info!("server running on {}://{}:{}", scheme, address.0, address.1);
     server.await
 }
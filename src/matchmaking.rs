@@ -0,0 +1,186 @@
+use std::time::{Duration, Instant};
+
+/// A user waiting in the quick-play queue
+#[derive(Debug, Clone, Copy)]
+pub struct Waiting {
+    pub id: u128,
+    pub room_id: u128, // room the user is waiting in
+    pub rating: f64,
+    pub since: Instant,
+}
+
+/// Quick-play queue grouping waiting users into rooms
+pub struct Matchmaker {
+    pub queue: Vec<Waiting>, // oldest first
+    pub target_size: usize,  // number of players we try to put in a room
+    pub max_wait: Duration,  // after this we start a room with whoever is waiting
+    pub base_tolerance: f64, // rating difference accepted right after joining the queue
+    pub tolerance_growth: f64, // rating difference accepted on top for every second waited
+}
+
+impl Default for Matchmaker {
+    fn default() -> Self {
+        Matchmaker {
+            queue: Vec::new(),
+            target_size: 4,
+            max_wait: Duration::from_secs(10),
+            base_tolerance: 100.0,
+            tolerance_growth: 50.0,
+        }
+    }
+}
+
+impl Matchmaker {
+    /// Put user in the queue, returns false if they were already waiting
+    pub fn enqueue(&mut self, id: u128, room_id: u128, rating: f64) -> bool {
+        if self.contains(id) {
+            return false;
+        }
+        self.queue.push(Waiting {
+            id,
+            room_id,
+            rating,
+            since: Instant::now(),
+        });
+        true
+    }
+
+    /// Take user out of the queue, returns false if they weren't waiting
+    pub fn remove(&mut self, id: u128) -> bool {
+        let len = self.queue.len();
+        self.queue.retain(|x| x.id != id);
+        len != self.queue.len()
+    }
+
+    pub fn contains(&self, id: u128) -> bool {
+        self.queue.iter().any(|x| x.id == id)
+    }
+
+    /// Rating difference user `waiting` accepts, widening the longer they wait
+    fn tolerance(&self, waiting: &Waiting, now: Instant) -> f64 {
+        self.base_tolerance + self.tolerance_growth * now.duration_since(waiting.since).as_secs_f64()
+    }
+
+    /// Take up to `n` of the longest waiting users accepting to play with someone rated `rating`
+    pub fn take_near(&mut self, rating: f64, n: usize, now: Instant) -> Vec<Waiting> {
+        let picked = self
+            .queue
+            .iter()
+            .filter(|x| (x.rating - rating).abs() <= self.tolerance(x, now))
+            .take(n)
+            .map(|x| x.id)
+            .collect::<Vec<_>>();
+        self.take_ids(&picked)
+    }
+
+    /// Take a group for a new room if there are enough similarly rated users waiting or someone waited too long
+    pub fn next_group(&mut self, now: Instant) -> Option<Vec<Waiting>> {
+        // try to build a group around every waiting user, longest waiting first
+        for anchor in self.queue.iter() {
+            let tolerance = self.tolerance(anchor, now);
+            let group = self
+                .queue
+                .iter()
+                .filter(|x| (x.rating - anchor.rating).abs() <= tolerance)
+                .take(self.target_size)
+                .map(|x| x.id)
+                .collect::<Vec<_>>();
+            if group.len() >= self.target_size || now.duration_since(anchor.since) >= self.max_wait {
+                return Some(self.take_ids(&group));
+            }
+        }
+        None
+    }
+
+    /// Take the users with the given ids out of the queue, keeping their order
+    fn take_ids(&mut self, ids: &[u128]) -> Vec<Waiting> {
+        let (taken, waiting) = self.queue.drain(..).partition(|x| ids.contains(&x.id));
+        self.queue = waiting;
+        taken
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matchmaker(target_size: usize) -> Matchmaker {
+        Matchmaker {
+            target_size,
+            max_wait: Duration::from_secs(60),
+            ..Default::default()
+        }
+    }
+
+    fn ids(group: &[Waiting]) -> Vec<u128> {
+        group.iter().map(|x| x.id).collect()
+    }
+
+    #[test]
+    fn groups_similarly_rated_users_once_enough_are_waiting() {
+        let mut mm = matchmaker(3);
+        let now = Instant::now();
+        mm.enqueue(1, 0, 1500.0);
+        mm.enqueue(2, 0, 1550.0);
+        assert!(mm.next_group(now).is_none());
+        mm.enqueue(3, 0, 1450.0);
+        assert_eq!(ids(&mm.next_group(now).unwrap()), vec![1, 2, 3]);
+        assert!(mm.queue.is_empty());
+    }
+
+    #[test]
+    fn keeps_users_far_apart_in_rating_separate() {
+        let mut mm = matchmaker(2);
+        let now = Instant::now();
+        mm.enqueue(1, 0, 1000.0);
+        mm.enqueue(2, 0, 2000.0);
+        mm.enqueue(3, 0, 2050.0);
+        assert_eq!(ids(&mm.next_group(now).unwrap()), vec![2, 3]);
+        assert_eq!(ids(&mm.queue), vec![1]);
+    }
+
+    #[test]
+    fn widens_the_rating_window_while_waiting() {
+        let mut mm = matchmaker(2);
+        mm.enqueue(1, 0, 1500.0);
+        mm.enqueue(2, 0, 1800.0);
+        let now = Instant::now();
+        // 100 accepted right away, 50 more for every second waited
+        assert!(mm.next_group(now).is_none());
+        assert!(mm.next_group(now + Duration::from_secs(3)).is_none());
+        let group = mm.next_group(now + Duration::from_secs(5)).unwrap();
+        assert_eq!(ids(&group), vec![1, 2]);
+    }
+
+    #[test]
+    fn starts_a_smaller_room_after_waiting_too_long() {
+        let mut mm = matchmaker(4);
+        mm.max_wait = Duration::from_secs(10);
+        mm.enqueue(1, 0, 1500.0);
+        mm.enqueue(2, 0, 1500.0);
+        let now = Instant::now(); // both waited a little longer than this
+        assert!(mm.next_group(now + Duration::from_secs(9)).is_none());
+        assert_eq!(ids(&mm.next_group(now + Duration::from_secs(10)).unwrap()), vec![1, 2]);
+    }
+
+    #[test]
+    fn tops_up_with_the_longest_waiting_users_near_the_rating() {
+        let mut mm = matchmaker(4);
+        let now = Instant::now();
+        mm.enqueue(1, 0, 1500.0);
+        mm.enqueue(2, 0, 1900.0);
+        mm.enqueue(3, 0, 1520.0);
+        mm.enqueue(4, 0, 1480.0);
+        assert_eq!(ids(&mm.take_near(1500.0, 2, now)), vec![1, 3]);
+        assert_eq!(ids(&mm.queue), vec![2, 4]);
+    }
+
+    #[test]
+    fn queues_a_user_only_once() {
+        let mut mm = matchmaker(4);
+        assert!(mm.enqueue(1, 0, 1500.0));
+        assert!(!mm.enqueue(1, 0, 1500.0));
+        assert!(mm.remove(1));
+        assert!(!mm.remove(1));
+    }
+}
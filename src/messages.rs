@@ -2,6 +2,17 @@ use actix::{
     prelude::{Message, Recipient},
     Actor, Addr, Handler,
 };
+use actix_web_actors::ws::CloseReason;
+use bytestring::ByteString;
+use serde::Serialize;
+use std::time::Duration;
+
+use crate::{
+    error::LobbyError,
+    game::game::GameConfig,
+    room::{RoomAccess, RoomInfo},
+    session::SessionInfo,
+};
 use uuid::Uuid;
 
 //WsConn responds to this to pipe it through to the actual client
@@ -9,32 +20,229 @@ use uuid::Uuid;
 #[rtype(result = "()")]
 pub struct WsMessage(pub String);
 
-//WsConn sends this to the lobby to say "put me in please"
+//WsConn responds to this by sending the game snapshot to the client, the public buffer is shared by every recipient
+//players also get their own view as a separate message, spectators only the public snapshot
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct Snapshot {
+    pub public: ByteString,
+    pub own: Option<ByteString>,
+}
+
+//WsConn responds to this by closing the connection to the client with the given reason
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct WsClose(pub Option<CloseReason>);
+
+//lobby sends this to WsConn when it moved the user into another room
 #[derive(Message)]
 #[rtype(result = "()")]
+pub struct JoinedRoom {
+    pub room_id: u128,
+    pub game: Option<Recipient<ClientActorMessage>>, // where inputs go from now on, None for spectators
+}
+
+//lobby answers Connect with where the connection ended up, the ids differ from the requested ones when resuming
+pub struct Joined {
+    pub id: u128,
+    pub room_id: u128,
+    pub game: Option<Recipient<ClientActorMessage>>, // actor of the room the inputs go to, None for spectators
+}
+
+//WsConn sends this to the lobby to say "put me in please", the lobby answers where the connection ended up
+//or the reason if it refuses
+#[derive(Message)]
+#[rtype(result = "Result<Joined, LobbyError>")]
 pub struct Connect<A>
 where
-    A: Actor + Handler<WsMessage>,
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
 {
     // pub addr: Recipient<WsMessage>,
     pub addr: Addr<A>,
-    pub lobby_id: u128,
+    pub lobby_id: Option<u128>, // None to open a new room with an id picked by the lobby
     pub self_id: u128,
+    pub access: RoomAccess,
 }
 
 //WsConn sends this to a lobby to say "take me out please"
 #[derive(Message)]
 #[rtype(result = "()")]
-pub struct Disconnect {
+pub struct Disconnect<A>
+where
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+{
+    pub addr: Addr<A>, // connection going away, ignored if the session was resumed by another one
     pub room_id: u128,
     pub id: u128,
 }
 
-//client sends this to the lobby for the lobby to echo out.
+//WsConn sends this to the lobby whenever it measured the round trip time to its client
 #[derive(Message)]
 #[rtype(result = "()")]
+pub struct Latency {
+    pub id: u128,
+    pub room_id: u128,
+    pub rtt: Duration,
+    pub idle: Duration, // since the client sent its last message
+}
+
+//client sends this to the lobby for chat and commands and straight to its room for inputs, errors are sent back
+//to the client as well
+#[derive(Message)]
+#[rtype(result = "Result<(), LobbyError>")]
 pub struct ClientActorMessage {
     pub id: u128,
     pub msg: String,
     pub room_id: u128,
 }
+
+//lobby sends this to a room to hand it the connection of a user that joined or resumed
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Attach<A>
+where
+    A: Actor + Handler<WsMessage> + Handler<WsClose> + Handler<JoinedRoom> + Handler<Snapshot>,
+{
+    pub id: u128,
+    pub addr: Addr<A>,
+}
+
+//lobby sends this to a room when the connection of a user dropped, the user keeps their slot in the game
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Detach {
+    pub id: u128,
+}
+
+//lobby sends this to a room when a user left it for good
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Leave {
+    pub id: u128,
+}
+
+//lobby sends this to a room when the host starts the game
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct StartGame;
+
+//lobby sends this to a room to end its round right away, e.g. when the server shuts down
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct EndRound;
+
+//lobby sends this to a room once nobody is left in it
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct CloseRoom;
+
+//lobby sends this to a room when the host changes how far behind the game spectators are
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SpectatorDelay(pub Duration);
+
+//room sends this to the lobby when a round started
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RoundStarted {
+    pub room_id: u128,
+}
+
+//room sends this to the lobby with the placements (best first) when a round is over
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RoundOver {
+    pub room_id: u128,
+    pub placements: Vec<Vec<u128>>,
+}
+
+//room sends this to the lobby to get a user out of the room, e.g. for idling
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Kick {
+    pub room_id: u128,
+    pub id: u128,
+    pub reason: String,
+}
+
+//main sends this to the lobby on SIGTERM, answered once running rounds finished (or were ended after `grace`)
+//and every connection was closed
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Shutdown {
+    pub grace: Duration,
+}
+
+//lobby sends this to a room to learn how long ago its game was updated last
+#[derive(Message)]
+#[rtype(result = "Duration")]
+pub struct LastTick;
+
+//endpoint sends this to the lobby to check if the server can take traffic
+#[derive(Message)]
+#[rtype(result = "Readiness")]
+pub struct CheckReady;
+
+//lobby answers CheckReady with this
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub shutting_down: bool,
+    pub stalled: Vec<u128>, // rooms whose game isn't updated anymore or that didn't answer in time
+}
+
+impl Readiness {
+    pub fn ready(&self) -> bool {
+        !self.shutting_down && self.stalled.is_empty()
+    }
+}
+
+//endpoint sends this to the lobby to find the id of the room with a join code, None if there is no such room
+#[derive(Message)]
+#[rtype(result = "Option<u128>")]
+pub struct FindRoom(pub String);
+
+//endpoint sends this to the lobby to get the publicly listed rooms
+#[derive(Message)]
+#[rtype(result = "Vec<RoomInfo>")]
+pub struct ListRooms;
+
+//admin endpoint sends this to the lobby to get every session, connected or waiting to be resumed
+#[derive(Message)]
+#[rtype(result = "Vec<SessionInfo>")]
+pub struct ListSessions;
+
+//admin endpoint sends this to the lobby to close the connection of a user and end their session
+#[derive(Message)]
+#[rtype(result = "Result<(), LobbyError>")]
+pub struct ForceDisconnect {
+    pub id: u128,
+}
+
+//admin endpoint sends this to the lobby to disconnect everyone in a room, which closes it
+#[derive(Message)]
+#[rtype(result = "Result<(), LobbyError>")]
+pub struct ForceCloseRoom {
+    pub room_id: u128,
+}
+
+//admin endpoint sends this to the lobby to abort the round of a room without results, the lobby passes it
+//on to the room
+#[derive(Message)]
+#[rtype(result = "Result<(), LobbyError>")]
+pub struct ResetRoom {
+    pub room_id: u128,
+}
+
+//admin endpoint sends this to the lobby to change the rules of a room between rounds, the lobby passes it
+//on to the room
+#[derive(Message)]
+#[rtype(result = "Result<(), LobbyError>")]
+pub struct SetGameConfig {
+    pub room_id: u128,
+    pub config: GameConfig,
+}
+
+//admin endpoint sends this to the lobby to tell every connected user something
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Announce(pub String);
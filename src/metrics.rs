@@ -0,0 +1,77 @@
+use prometheus::{
+    Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::sync::LazyLock;
+
+/// Metrics of the server, scraped from `/metrics`
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    pub registry: Registry,
+    pub sessions: IntGauge,          // open websocket connections
+    pub rooms: IntGaugeVec,          // rooms by phase, `waiting` or `running`
+    pub room_players: IntGaugeVec,   // players in the game of each room
+    pub tick_duration: Histogram,    // time a room takes to update its game and send the snapshots
+    pub snapshot_bytes: IntCounter,  // bytes of snapshots handed to the connections
+    pub snapshots_skipped: IntCounter, // snapshots not taken by connections that can't keep up
+    pub messages: IntCounterVec,     // messages received from clients by kind
+    pub rate_limited: IntCounterVec, // messages rejected by the rate limits by kind
+    pub mailbox_failures: IntCounterVec, // messages that couldn't be delivered to an actor, by receiver
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some(String::from("flappybird")), None).unwrap();
+        let metrics = Metrics {
+            sessions: IntGauge::new("sessions", "Open websocket connections").unwrap(),
+            rooms: IntGaugeVec::new(Opts::new("rooms", "Rooms by phase"), &["phase"]).unwrap(),
+            room_players: IntGaugeVec::new(Opts::new("room_players", "Players in the game of a room"), &["room"])
+                .unwrap(),
+            tick_duration: Histogram::with_opts(
+                HistogramOpts::new("tick_duration_seconds", "Time to update a game and send its snapshots")
+                    .buckets(vec![0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.016, 0.033]),
+            )
+            .unwrap(),
+            snapshot_bytes: IntCounter::new("snapshot_bytes_total", "Bytes of game snapshots sent").unwrap(),
+            snapshots_skipped: IntCounter::new(
+                "snapshots_skipped_total",
+                "Snapshots skipped for connections that can't keep up",
+            )
+            .unwrap(),
+            messages: IntCounterVec::new(Opts::new("messages_total", "Messages received by kind"), &["kind"]).unwrap(),
+            rate_limited: IntCounterVec::new(
+                Opts::new("rate_limited_total", "Messages rejected by the rate limits by kind"),
+                &["kind"],
+            )
+            .unwrap(),
+            mailbox_failures: IntCounterVec::new(
+                Opts::new("mailbox_failures_total", "Messages that couldn't be delivered to an actor"),
+                &["receiver"],
+            )
+            .unwrap(),
+            registry,
+        };
+        let collectors: [Box<dyn prometheus::core::Collector>; 9] = [
+            Box::new(metrics.sessions.clone()),
+            Box::new(metrics.rooms.clone()),
+            Box::new(metrics.room_players.clone()),
+            Box::new(metrics.tick_duration.clone()),
+            Box::new(metrics.snapshot_bytes.clone()),
+            Box::new(metrics.snapshots_skipped.clone()),
+            Box::new(metrics.messages.clone()),
+            Box::new(metrics.rate_limited.clone()),
+            Box::new(metrics.mailbox_failures.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector).unwrap();
+        }
+        metrics
+    }
+
+    /// All metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        TextEncoder::new()
+            .encode_to_string(&self.registry.gather())
+            .unwrap_or_else(|err| format!("# can't encode metrics: {}\n", err))
+    }
+}
@@ -0,0 +1,226 @@
+use std::time::{Duration, Instant};
+
+use crate::metrics::METRICS;
+
+/// Kind of message a client sends, each kind has its own limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    Input,
+    Chat,
+    Command,
+}
+
+impl MessageKind {
+    pub fn of(text: &str) -> Self {
+        if text.starts_with("\\w") {
+            MessageKind::Chat
+        } else if text.starts_with('!') {
+            MessageKind::Command
+        } else {
+            MessageKind::Input
+        }
+    }
+
+    /// Name of the kind in the metrics
+    pub fn label(&self) -> &'static str {
+        match self {
+            MessageKind::Input => "input",
+            MessageKind::Chat => "chat",
+            MessageKind::Command => "command",
+        }
+    }
+}
+
+/// Sustained rate and burst allowed for one kind of message
+#[derive(Debug, Clone, Copy)]
+pub struct Limit {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+impl Limit {
+    /// Limit of `per_second` messages, allowing bursts of twice that but at least a single message
+    pub fn per_second(per_second: f64) -> Self {
+        Limit {
+            per_second,
+            burst: (2.0 * per_second).max(1.0),
+        }
+    }
+}
+
+/// Limits of every kind of message and how much abuse we tolerate before disconnecting
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimits {
+    pub input: Limit,
+    pub chat: Limit,
+    pub command: Limit,
+    pub strikes: Limit, // rejected messages we put up with before disconnecting the client
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            input: Limit::per_second(30.0),
+            chat: Limit::per_second(2.0),
+            command: Limit::per_second(5.0),
+            strikes: Limit {
+                per_second: 1.0,
+                burst: 20.0,
+            },
+        }
+    }
+}
+
+/// Classic token bucket, filling up at a constant rate up to its capacity
+#[derive(Debug, Clone, Copy)]
+pub struct TokenBucket {
+    limit: Limit,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(limit: Limit) -> Self {
+        TokenBucket {
+            limit,
+            tokens: limit.burst,
+            last: Instant::now(),
+        }
+    }
+
+    /// Take a token if there is one left
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        let refill = now.duration_since(self.last).as_secs_f64() * self.limit.per_second;
+        self.tokens = (self.tokens + refill).min(self.limit.burst);
+        self.last = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// What to do with a message of the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    Reject,
+    Warn,       // reject and let the client know it is sending too much
+    Disconnect, // client keeps flooding us
+}
+
+/// Rate limits of a single connection
+pub struct RateLimiter {
+    input: TokenBucket,
+    chat: TokenBucket,
+    command: TokenBucket,
+    strikes: TokenBucket,
+    last_warning: Option<Instant>,
+    pub rejected: u64, // messages rejected on this connection
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        RateLimiter {
+            input: TokenBucket::new(limits.input),
+            chat: TokenBucket::new(limits.chat),
+            command: TokenBucket::new(limits.command),
+            strikes: TokenBucket::new(limits.strikes),
+            last_warning: None,
+            rejected: 0,
+        }
+    }
+
+    /// Decide what to do with a message of `kind` received at `now`
+    pub fn check(&mut self, kind: MessageKind, now: Instant) -> Verdict {
+        let bucket = match kind {
+            MessageKind::Input => &mut self.input,
+            MessageKind::Chat => &mut self.chat,
+            MessageKind::Command => &mut self.command,
+        };
+        if bucket.try_take(now) {
+            return Verdict::Accept;
+        }
+
+        self.rejected += 1;
+        METRICS.rate_limited.with_label_values(&[kind.label()]).inc();
+        if !self.strikes.try_take(now) {
+            return Verdict::Disconnect;
+        }
+        // warn at most once a second
+        if self.last_warning.is_none_or(|x| now.duration_since(x) >= Duration::from_secs(1)) {
+            self.last_warning = Some(now);
+            return Verdict::Warn;
+        }
+        Verdict::Reject
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> RateLimits {
+        RateLimits {
+            input: Limit::per_second(10.0),
+            chat: Limit::per_second(0.5),
+            command: Limit::per_second(5.0),
+            strikes: Limit {
+                per_second: 1.0,
+                burst: 3.0,
+            },
+        }
+    }
+
+    #[test]
+    fn bucket_allows_a_burst_then_refuses() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(Limit::per_second(10.0));
+        assert_eq!((0..25).filter(|_| bucket.try_take(now)).count(), 20);
+    }
+
+    #[test]
+    fn bucket_refills_at_its_rate_up_to_the_burst() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(Limit::per_second(10.0));
+        while bucket.try_take(now) {}
+        let later = now + Duration::from_millis(500);
+        assert_eq!((0..10).filter(|_| bucket.try_take(later)).count(), 5);
+        let much_later = later + Duration::from_secs(60);
+        assert_eq!((0..25).filter(|_| bucket.try_take(much_later)).count(), 20);
+    }
+
+    #[test]
+    fn slow_rates_still_allow_a_single_message() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(Limit::per_second(0.2));
+        assert!(bucket.try_take(now));
+        assert!(!bucket.try_take(now));
+        assert!(bucket.try_take(now + Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn verdicts_escalate_from_warning_to_disconnect() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(limits());
+        assert_eq!(limiter.check(MessageKind::Chat, now), Verdict::Accept);
+        assert_eq!(limiter.check(MessageKind::Chat, now), Verdict::Warn);
+        assert_eq!(limiter.check(MessageKind::Chat, now), Verdict::Reject);
+        assert_eq!(limiter.check(MessageKind::Chat, now), Verdict::Reject);
+        assert_eq!(limiter.check(MessageKind::Chat, now), Verdict::Disconnect);
+        assert_eq!(limiter.rejected, 4);
+    }
+
+    #[test]
+    fn warns_again_a_second_later() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(limits());
+        limiter.check(MessageKind::Chat, now);
+        assert_eq!(limiter.check(MessageKind::Chat, now), Verdict::Warn);
+        assert_eq!(limiter.check(MessageKind::Chat, now), Verdict::Reject);
+        let later = now + Duration::from_secs(1);
+        assert_eq!(limiter.check(MessageKind::Command, later), Verdict::Accept);
+        assert_eq!(limiter.check(MessageKind::Chat, later), Verdict::Warn);
+    }
+}
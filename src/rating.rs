@@ -0,0 +1,229 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use actix_web::rt::task::{spawn_blocking, JoinHandle};
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use crate::{error::LobbyError, session::random_token};
+
+/// Elo rating of a single player
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Rating {
+    pub rating: f64,
+    pub matches: u32,
+}
+
+impl Default for Rating {
+    fn default() -> Self {
+        Rating {
+            rating: 1500.0,
+            matches: 0,
+        }
+    }
+}
+
+/// Rating of a name and the token proving ownership of it
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Entry {
+    #[serde(flatten)]
+    rating: Rating,
+    #[serde(default)]
+    token: Option<String>, // handed to the first player using the name, needed to play under it again
+}
+
+/// Ratings of every named player, persisted as json on disk
+pub struct Ratings {
+    path: PathBuf,
+    players: HashMap<String, Entry>, // player name -> rating
+    k: f64, // maximum rating change of a single match
+    saves: u64, // number of saves started
+    written: Arc<Mutex<u64>>, // number of the save last written to disk, older ones don't overwrite it
+}
+
+impl Ratings {
+    /// Load ratings from `path`, starting from scratch if there is no such file yet
+    pub fn load(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let players = match fs::read_to_string(&path) {
+            Ok(data) => serde_json::from_str(&data).unwrap_or_else(|err| {
+                warn!(path = %path.display(), error = %err, "ratings can't be parsed, starting from scratch");
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        Ratings {
+            path,
+            players,
+            k: 32.0,
+            saves: 0,
+            written: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    /// Write the ratings to disk on a blocking thread, keeping the lobby responsive. They go to a temporary
+    /// file first and replace the old one at once, so a crash can't leave half a file behind
+    pub fn save(&mut self) -> JoinHandle<()> {
+        self.saves += 1;
+        let save = self.saves;
+        let data = serde_json::to_string_pretty(&self.players).unwrap();
+        let path = self.path.clone();
+        let written = self.written.clone();
+        spawn_blocking(move || {
+            let mut written = written.lock().unwrap();
+            if *written > save {
+                return; // newer ratings are on disk already
+            }
+            let tmp = path.with_extension("tmp");
+            match fs::write(&tmp, data).and_then(|_| fs::rename(&tmp, &path)) {
+                Ok(()) => *written = save,
+                Err(err) => error!(path = %path.display(), error = %err, "ratings can't be saved"),
+            }
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Rating {
+        self.players.get(name).map(|x| x.rating).unwrap_or_default()
+    }
+
+    /// Check the player may use `name`: once a name was handed a token, only whoever holds it can play under it
+    pub fn verify(&self, name: &str, token: Option<&str>) -> Result<(), LobbyError> {
+        match self.players.get(name).and_then(|x| x.token.as_deref()) {
+            Some(owner) if Some(owner) != token => Err(LobbyError::NameTaken(name.to_string())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Make `name` owned by the player using it, returns the token to hand them if nobody owned it yet
+    pub fn claim(&mut self, name: &str) -> Option<String> {
+        let entry = self.players.entry(name.to_string()).or_default();
+        if entry.token.is_some() {
+            return None;
+        }
+        let token = random_token(32);
+        entry.token = Some(token.clone());
+        Some(token)
+    }
+
+    /// Update ratings from the `placements` of a match, best first with ties sharing a place.
+    /// Every player is scored against every other player (multiplayer Elo), returns the change per player
+    pub fn update(&mut self, placements: &[Vec<String>]) -> Vec<(String, f64)> {
+        let ranked = placements
+            .iter()
+            .enumerate()
+            .flat_map(|(place, names)| names.iter().map(move |name| (name, place)))
+            .map(|(name, place)| (name.clone(), place, self.get(name).rating))
+            .collect::<Vec<_>>();
+        if ranked.len() < 2 {
+            return Vec::new();
+        }
+
+        let opponents = (ranked.len() - 1) as f64;
+        let changes = ranked
+            .iter()
+            .map(|(name, place, rating)| {
+                let delta: f64 = ranked
+                    .iter()
+                    .filter(|(other, _, _)| other != name)
+                    .map(|(_, other_place, other_rating)| {
+                        let score = match place.cmp(other_place) {
+                            std::cmp::Ordering::Less => 1.0,
+                            std::cmp::Ordering::Equal => 0.5,
+                            std::cmp::Ordering::Greater => 0.0,
+                        };
+                        let expected = 1.0 / (1.0 + 10f64.powf((other_rating - rating) / 400.0));
+                        score - expected
+                    })
+                    .sum();
+                (name.clone(), self.k * delta / opponents)
+            })
+            .collect::<Vec<_>>();
+
+        for (name, delta) in changes.iter() {
+            let entry = &mut self.players.entry(name.clone()).or_default().rating;
+            entry.rating += delta;
+            entry.matches += 1;
+        }
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rated(players: &[(&str, f64)]) -> Ratings {
+        let mut ratings = Ratings::load("");
+        for (name, rating) in players {
+            ratings.players.entry(name.to_string()).or_default().rating.rating = *rating;
+        }
+        ratings
+    }
+
+    fn names(placements: &[&[&str]]) -> Vec<Vec<String>> {
+        placements
+            .iter()
+            .map(|x| x.iter().map(|x| x.to_string()).collect())
+            .collect()
+    }
+
+    fn change(changes: &[(String, f64)], name: &str) -> f64 {
+        changes.iter().find(|(x, _)| x == name).unwrap().1
+    }
+
+    #[test]
+    fn changes_add_up_to_zero() {
+        let mut ratings = rated(&[("a", 1700.0), ("b", 1500.0), ("c", 1350.0), ("d", 1500.0)]);
+        let changes = ratings.update(&names(&[&["c"], &["a", "d"], &["b"]]));
+        assert_eq!(changes.len(), 4);
+        assert!(changes.iter().map(|(_, x)| x).sum::<f64>().abs() < 1e-9);
+    }
+
+    #[test]
+    fn better_places_gain_more() {
+        let mut ratings = rated(&[]);
+        let changes = ratings.update(&names(&[&["a"], &["b"], &["c"]]));
+        assert!(change(&changes, "a") > 0.0);
+        assert!(change(&changes, "a") > change(&changes, "b"));
+        assert!(change(&changes, "b") > change(&changes, "c"));
+        assert!(change(&changes, "c") < 0.0);
+        assert!(ratings.get("a").rating > ratings.get("c").rating);
+        assert_eq!(ratings.get("b").matches, 1);
+    }
+
+    #[test]
+    fn ties_share_the_place() {
+        let mut ratings = rated(&[]);
+        let changes = ratings.update(&names(&[&["a", "b"]]));
+        assert!(change(&changes, "a").abs() < 1e-9);
+        assert!(change(&changes, "b").abs() < 1e-9);
+
+        // a tie against a better rated player still gains rating
+        let mut ratings = rated(&[("a", 1800.0), ("b", 1500.0)]);
+        let changes = ratings.update(&names(&[&["a", "b"]]));
+        assert!(change(&changes, "a") < 0.0);
+        assert!(change(&changes, "b") > 0.0);
+    }
+
+    #[test]
+    fn single_player_is_not_rated() {
+        let mut ratings = rated(&[]);
+        assert!(ratings.update(&names(&[&["a"]])).is_empty());
+        assert_eq!(ratings.get("a").matches, 0);
+    }
+
+    #[test]
+    fn claimed_names_need_their_token() {
+        let mut ratings = rated(&[]);
+        assert_eq!(ratings.verify("a", None), Ok(()));
+        let token = ratings.claim("a").unwrap();
+        assert_eq!(ratings.claim("a"), None);
+        assert_eq!(ratings.verify("a", Some(&token)), Ok(()));
+        assert_eq!(ratings.verify("a", None), Err(LobbyError::NameTaken("a".to_string())));
+        assert_eq!(ratings.verify("a", Some("guess")), Err(LobbyError::NameTaken("a".to_string())));
+    }
+}
@@ -0,0 +1,300 @@
+use std::{collections::HashSet, net::IpAddr, str::FromStr, time::Duration};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{error::LobbyError, game_room::MAX_SPECTATOR_DELAY, session::random_token};
+
+/// Who can find and join a room
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    #[default]
+    Public,   // listed and joinable by anyone
+    Unlisted, // joinable by anyone who knows the room id
+    Private,  // joinable only with an invite code or the password
+}
+
+impl FromStr for Visibility {
+    type Err = LobbyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "public" => Ok(Visibility::Public),
+            "unlisted" => Ok(Visibility::Unlisted),
+            "private" => Ok(Visibility::Private),
+            _ => Err(LobbyError::UnknownVisibility(s.to_string())),
+        }
+    }
+}
+
+/// What a connection is in the room for
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    Player,    // joins the game with their first input
+    Spectator, // only watches, inputs and readying up are refused
+}
+
+/// Characters of the join codes, leaving out those easily confused when read aloud (0/O, 1/I/L)
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKMNPQRSTUVWXYZ23456789";
+pub const CODE_LEN: usize = 6;
+
+/// Random join code, with at least one letter so it can't be mistaken for a numeric room id
+pub fn random_code() -> String {
+    let mut rng = rand::thread_rng();
+    loop {
+        let code = (0..CODE_LEN)
+            .map(|_| CODE_ALPHABET[rng.gen_range(0..CODE_ALPHABET.len())] as char)
+            .collect::<String>();
+        if !code.bytes().all(|x| x.is_ascii_digit()) {
+            return code;
+        }
+    }
+}
+
+/// Join code as typed by a user in its canonical upper case form, None if it can't be one
+pub fn parse_code(s: &str) -> Option<String> {
+    let code = s.to_ascii_uppercase();
+    let valid = code.len() == CODE_LEN
+        && code.bytes().all(|x| CODE_ALPHABET.contains(&x))
+        && !code.bytes().all(|x| x.is_ascii_digit());
+    valid.then_some(code)
+}
+
+/// Who a ban applies to, users get a new id with every connection so their name and address are banned too
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Banned {
+    Id(u128),
+    Name(String),
+    Address(IpAddr),
+}
+
+/// Access options a client connects with, taken from the query string of the websocket endpoint
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RoomAccess {
+    pub password: Option<String>,
+    pub invite: Option<String>,
+    pub visibility: Option<Visibility>, // only used if the room gets created by this connection
+    pub name: Option<String>, // player name, ratings are kept per name
+    pub rating_token: Option<String>, // proves the name belongs to the player, handed out when first using it
+    pub resume: Option<String>, // resume token of a session to reattach to
+    pub spectator_delay: Option<u64>, // seconds, only used if the room gets created by this connection
+    #[serde(default)]
+    pub quickplay: bool, // put the connection in the quick-play queue once joined
+    #[serde(default)]
+    pub role: Role,
+    pub version: Option<u32>, // protocol version the client speaks, the current one if not given
+    #[serde(skip)]
+    pub address: Option<IpAddr>, // of the client, taken from the connection rather than the query string
+}
+
+/// Public information about a room as shown in the room list
+#[derive(Debug, Clone, Serialize)]
+pub struct RoomInfo {
+    pub id: String, // u128 doesn't fit a JSON number
+    pub code: String,
+    pub users: usize,
+    pub running: bool,
+    pub locked: bool,
+    pub password: bool,
+}
+
+/// A single room in the lobby; the users connected to it, the game they play and who moderates it
+pub struct Room {
+    pub code: String, // short join code, handed out by the lobby when the room is opened
+    pub users: HashSet<u128>,
+    pub running: bool, // mirrors the game running in the actor of the room
+    pub host: Option<u128>, // user allowed to run moderation commands
+    pub shared: bool, // the quick-play waiting room belongs to everyone, it never gets a host
+    pub banned: HashSet<Banned>, // users refused for the lifetime of the room
+    pub locked: bool, // locked rooms refuse any new connection
+    pub visibility: Visibility,
+    pub password: Option<String>,
+    pub invite_code: Option<String>,
+    pub quickplay: bool, // room was opened by matchmaking and gets topped up with waiting users
+    pub spectator_delay: Option<Duration>, // overrides the delay of the room config
+}
+
+impl Room {
+    pub fn new(host: Option<u128>) -> Self {
+        Room {
+            code: String::new(),
+            users: HashSet::new(),
+            running: false,
+            host,
+            shared: false,
+            banned: HashSet::new(),
+            locked: false,
+            visibility: Visibility::default(),
+            password: None,
+            invite_code: None,
+            quickplay: false,
+            spectator_delay: None,
+        }
+    }
+
+    /// Create the room quick-play users wait in, nobody can moderate it
+    pub fn waiting() -> Self {
+        Room {
+            shared: true,
+            ..Room::new(None)
+        }
+    }
+
+    /// Create a room configured with the access options of its creator
+    pub fn with_access(access: &RoomAccess) -> Self {
+        let mut room = Room::new(None);
+        room.visibility = access.visibility.unwrap_or_default();
+        room.password = access.password.clone().filter(|x| !x.is_empty());
+        room.spectator_delay = access.spectator_delay.map(|x| Duration::from_secs(x).min(MAX_SPECTATOR_DELAY));
+        if room.visibility == Visibility::Private {
+            room.new_invite_code();
+        }
+        room
+    }
+
+    /// Generate a new invite code for the room, invalidating the previous one
+    pub fn new_invite_code(&mut self) -> String {
+        let code = random_token(8);
+        self.invite_code = Some(code.clone());
+        code
+    }
+
+    pub fn info(&self, id: u128) -> RoomInfo {
+        RoomInfo {
+            id: id.to_string(),
+            code: self.code.clone(),
+            users: self.users.len(),
+            running: self.running,
+            locked: self.locked,
+            password: self.password.is_some(),
+        }
+    }
+
+    pub fn is_host(&self, id: u128) -> bool {
+        self.host == Some(id)
+    }
+
+    /// Check if user with `id` connecting with `access` was banned, by id, name or address
+    pub fn is_banned(&self, id: u128, access: &RoomAccess) -> bool {
+        self.banned.contains(&Banned::Id(id))
+            || access
+                .name
+                .as_ref()
+                .is_some_and(|x| self.banned.contains(&Banned::Name(x.clone())))
+            || access
+                .address
+                .is_some_and(|x| self.banned.contains(&Banned::Address(x)))
+    }
+
+    /// Check if user with `id` is allowed to join the room with the given `access`
+    pub fn can_join(&self, id: u128, access: &RoomAccess) -> Result<(), LobbyError> {
+        if self.is_banned(id, access) {
+            return Err(LobbyError::Banned);
+        }
+        if self.locked && !self.users.contains(&id) {
+            return Err(LobbyError::Locked);
+        }
+        // a valid invite code or the password lets you in regardless of visibility
+        let invited = self.invite_code.is_some() && access.invite == self.invite_code;
+        let authorised = self.password.is_some() && access.password == self.password;
+        if invited || authorised {
+            return Ok(());
+        }
+        if self.visibility == Visibility::Private {
+            return Err(LobbyError::PrivateRoom);
+        }
+        if self.password.is_some() {
+            return Err(LobbyError::WrongPassword);
+        }
+        Ok(())
+    }
+
+    /// Add user to the room, the first user to join an empty room without host becomes the host
+    pub fn join(&mut self, id: u128) {
+        self.users.insert(id);
+        if self.host.is_none() && !self.shared {
+            self.host = Some(id);
+        }
+    }
+
+    /// Remove user from the room, returns the new host if the host had to be migrated
+    pub fn leave(&mut self, id: u128) -> Option<u128> {
+        self.users.remove(&id);
+        if !self.is_host(id) {
+            return None;
+        }
+        // hand the room over to any remaining user -> None if the room is empty
+        self.host = self.users.iter().min().copied();
+        self.host
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_codes_parse_back_unchanged() {
+        for _ in 0..1000 {
+            let code = random_code();
+            assert_eq!(parse_code(&code), Some(code));
+        }
+    }
+
+    #[test]
+    fn random_codes_use_the_alphabet_and_contain_a_letter() {
+        for _ in 0..1000 {
+            let code = random_code();
+            assert_eq!(code.len(), CODE_LEN);
+            assert!(code.bytes().all(|x| CODE_ALPHABET.contains(&x)));
+            assert!(code.bytes().any(|x| x.is_ascii_alphabetic()));
+            assert!(!code.contains(['0', 'O', '1', 'I', 'L']));
+        }
+    }
+
+    #[test]
+    fn codes_are_case_insensitive() {
+        assert_eq!(parse_code("ab3xyz"), Some(String::from("AB3XYZ")));
+        assert_eq!(parse_code("Ab3XyZ"), Some(String::from("AB3XYZ")));
+    }
+
+    #[test]
+    fn refuses_what_can_not_be_a_code() {
+        assert_eq!(parse_code("AB3XY"), None);
+        assert_eq!(parse_code("AB3XYZW"), None);
+        assert_eq!(parse_code(""), None);
+        assert_eq!(parse_code("234567"), None); // numbers are never codes
+        assert_eq!(parse_code("AB0XYZ"), None); // 0 and O look alike
+        assert_eq!(parse_code("AB-XYZ"), None);
+    }
+
+    #[test]
+    fn bans_hold_for_new_ids_with_the_same_name_or_address() {
+        let mut room = Room::new(Some(1));
+        room.banned.insert(Banned::Id(2));
+        room.banned.insert(Banned::Name(String::from("mallory")));
+        room.banned.insert(Banned::Address([10, 0, 0, 2].into()));
+        let access = |name: Option<&str>, address: [u8; 4]| RoomAccess {
+            name: name.map(String::from),
+            address: Some(address.into()),
+            ..Default::default()
+        };
+        assert_eq!(room.can_join(2, &access(None, [10, 0, 0, 3])), Err(LobbyError::Banned));
+        assert_eq!(room.can_join(3, &access(Some("mallory"), [10, 0, 0, 3])), Err(LobbyError::Banned));
+        assert_eq!(room.can_join(3, &access(None, [10, 0, 0, 2])), Err(LobbyError::Banned));
+        assert_eq!(room.can_join(3, &access(Some("alice"), [10, 0, 0, 3])), Ok(()));
+    }
+
+    #[test]
+    fn waiting_room_never_gets_a_host() {
+        let mut room = Room::waiting();
+        room.join(1);
+        room.join(2);
+        assert_eq!(room.host, None);
+        assert_eq!(room.leave(1), None);
+        assert!(!room.is_host(2));
+    }
+}
@@ -0,0 +1,88 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use rand::{distributions::Alphanumeric, Rng};
+use serde::Serialize;
+
+/// Random alphanumeric string of length `len`
+pub fn random_token(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+/// A session as listed by the admin API
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    pub id: String, // u128 doesn't fit a JSON number
+    pub room: Option<String>,
+    pub name: Option<String>,
+    pub connected: bool, // false while waiting to be resumed
+    pub rtt_ms: Option<u64>, // last measured round trip time
+    pub idle_secs: u64, // since the last message of the user (inputs are reported with the heartbeat), or since the connection dropped
+}
+
+/// Keeps the slot of users whose websocket dropped for a grace period, so a new socket can resume it
+pub struct ResumableSessions {
+    pub tokens: HashMap<String, u128>, // resume token -> user_id
+    pub suspended: HashMap<u128, (u128, Instant)>, // user_id -> (room_id, disconnected at)
+    pub grace: Duration, // how long we keep the slot of a disconnected user
+}
+
+impl Default for ResumableSessions {
+    fn default() -> Self {
+        ResumableSessions {
+            tokens: HashMap::new(),
+            suspended: HashMap::new(),
+            grace: Duration::from_secs(30),
+        }
+    }
+}
+
+impl ResumableSessions {
+    /// Hand out a new resume token for user `id`, invalidating any previous one
+    pub fn issue(&mut self, id: u128) -> String {
+        self.tokens.retain(|_, x| *x != id);
+        let token = random_token(32);
+        self.tokens.insert(token.clone(), id);
+        token
+    }
+
+    /// Find the user the resume `token` belongs to
+    pub fn resolve(&self, token: &str) -> Option<u128> {
+        self.tokens.get(token).copied()
+    }
+
+    /// Keep the slot of user `id` in room `room_id` while they are gone
+    pub fn suspend(&mut self, id: u128, room_id: u128) {
+        self.suspended.insert(id, (room_id, Instant::now()));
+    }
+
+    /// User `id` is back, returns the room they were suspended in
+    pub fn resume(&mut self, id: u128) -> Option<u128> {
+        self.suspended.remove(&id).map(|(room_id, _)| room_id)
+    }
+
+    pub fn is_suspended(&self, id: u128) -> bool {
+        self.suspended.contains_key(&id)
+    }
+
+    /// Users whose grace period ran out as (user_id, room_id)
+    pub fn expired(&self, now: Instant) -> Vec<(u128, u128)> {
+        self.suspended
+            .iter()
+            .filter(|(_, (_, since))| now.duration_since(*since) >= self.grace)
+            .map(|(id, (room_id, _))| (*id, *room_id))
+            .collect()
+    }
+
+    /// Drop everything we know about user `id`
+    pub fn forget(&mut self, id: u128) {
+        self.suspended.remove(&id);
+        self.tokens.retain(|_, x| *x != id);
+    }
+}
@@ -1,35 +1,97 @@
 use actix::{
-    dev::ToEnvelope, fut, Actor, ActorContext, ActorFutureExt, Addr, AsyncContext,
-    ContextFutureSpawner, Handler, Message, Running, StreamHandler, WrapFuture,
+    fut, Actor, ActorContext, ActorFutureExt, Addr, AsyncContext, ContextFutureSpawner, Handler, Recipient,
+    Running, StreamHandler, WrapFuture,
 };
+use actix_http::ws::Item;
 use actix_web_actors::ws;
 use actix_web_actors::ws::Message::Text;
 use std::time::{Duration, Instant};
+use tracing::{error, info, info_span, warn, Span};
 use uuid::Uuid;
 
 use crate::{
     // lobby::Lobby,
+    error::LobbyError,
     game_lobby::Lobby,
-    messages::{ClientActorMessage, Connect, Disconnect, WsMessage},
+    messages::{ClientActorMessage, Connect, Disconnect, Joined, JoinedRoom, Latency, Snapshot, WsClose, WsMessage},
+    metrics::METRICS,
+    rate_limit::{MessageKind, RateLimiter, RateLimits, Verdict},
+    room::RoomAccess,
 };
 
+/// Version of the text protocol spoken over the websocket, clients asking for another one are refused
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Largest message we reassemble from continuation frames
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// How often we ping the client and how long we wait for any sign of life before dropping it
+#[derive(Debug, Clone, Copy)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(120),
+        }
+    }
+}
+
 // Will need an actor to handle the websocket connection.
 // use actix::Addr; // Addr is an alias for Addr<Actor>
 
 pub struct WsConn {
     pub room: u128,
+    pub requested_room: Option<u128>, // room asked for when connecting, None for a new room
     pub lobby_addr: Addr<Lobby<WsConn>>,
+    pub game: Option<Recipient<ClientActorMessage>>, // actor of our room, inputs skip the lobby; None for spectators
     pub hb: Instant,
+    pub last_message: Instant, // last text message of the client
+    pub heartbeat: Heartbeat,
+    pub rtt: Option<Duration>, // round trip time measured with the last answered ping
+    pub limiter: RateLimiter,
+    pub created: Instant, // our pings carry the time since the connection was created
     pub id: u128,
+    pub access: RoomAccess, // credentials/options used when joining the room
+    pub fragments: Option<Vec<u8>>, // payload so far of a text message split over continuation frames
+    pub snapshot: Option<Snapshot>, // newest snapshot waiting to be written, replaced by newer ones
+    pub span: Span, // carries the session and room ids to the log events of the connection
+}
+
+/// Span of the connection of user `id` in room `room`
+fn session_span(id: u128, room: u128) -> Span {
+    info_span!("session", session = %id, room = %room)
 }
 
 impl WsConn {
-    pub fn new(lobby_addr: Addr<Lobby<WsConn>>, room: Option<u128>) -> WsConn {
+    pub fn new(
+        lobby_addr: Addr<Lobby<WsConn>>,
+        room: Option<u128>,
+        access: RoomAccess,
+        heartbeat: Heartbeat,
+        limits: RateLimits,
+    ) -> WsConn {
+        let id = Uuid::new_v4().as_u128();
         WsConn {
-            room: room.unwrap_or(0),
+            room: room.unwrap_or(0), // until the lobby tells us where we ended up
+            requested_room: room,
             lobby_addr,
+            game: None,
             hb: Instant::now(),
-            id: Uuid::new_v4().as_u128(),
+            last_message: Instant::now(),
+            heartbeat,
+            rtt: None,
+            limiter: RateLimiter::new(limits),
+            created: Instant::now(),
+            id,
+            access,
+            fragments: None,
+            snapshot: None,
+            span: session_span(id, room.unwrap_or(0)),
         }
     }
 }
@@ -39,6 +101,7 @@ impl Actor for WsConn {
 
     // what to do when connection is opened
     fn started(&mut self, ctx: &mut Self::Context) {
+        METRICS.sessions.inc();
         // Spawn heartbeat job
         self.spawn_heartbeat_job(ctx);
 
@@ -48,15 +111,28 @@ impl Actor for WsConn {
             // We try to send the message to the lobby. -> Waiting it out
             .send(Connect {
                 addr: addr.clone(),
-                lobby_id: self.room,
+                lobby_id: self.requested_room,
                 self_id: self.id,
+                access: self.access.clone(),
             })
             // We convert to result to ourself
             .into_actor(self)
             // Handle the response
             .then(|res, _self, ctx| {
                 match res {
-                    Ok(_res) => (),  // if connection success do nothing
+                    // if connection success take over the ids the lobby gave us (differ when resuming)
+                    Ok(Ok(Joined { id, room_id, game })) => {
+                        _self.id = id;
+                        _self.room = room_id;
+                        _self.game = game;
+                        _self.span = session_span(id, room_id);
+                        info!(parent: &_self.span, "connected");
+                    }
+                    // the lobby refused us -> tell the client why before closing
+                    Ok(Err(reason)) => {
+                        info!(parent: &_self.span, %reason, "refused by the lobby");
+                        _self.close(ctx, ws::CloseCode::Policy, &reason.to_string())
+                    }
                     _ => ctx.stop(), // if failed -> stop the actor/connection
                 }
                 fut::ready(()) // construct return value
@@ -65,13 +141,19 @@ impl Actor for WsConn {
     }
 
     // What to do when the connection is closed
-    fn stopping(&mut self, _: &mut Self::Context) -> Running {
+    fn stopping(&mut self, ctx: &mut Self::Context) -> Running {
+        info!(parent: &self.span, "disconnected");
         self.lobby_addr.do_send(Disconnect {
+            addr: ctx.address(),
             id: self.id,
             room_id: self.room,
         });
         Running::Stop
     }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        METRICS.sessions.dec();
+    }
 }
 /// Handle Basic Websocket Message
 impl Handler<WsMessage> for WsConn {
@@ -81,22 +163,79 @@ impl Handler<WsMessage> for WsConn {
     }
 }
 
+/// Send the game snapshot shared with the rest of the room
+impl Handler<Snapshot> for WsConn {
+    type Result = ();
+
+    fn handle(&mut self, msg: Snapshot, ctx: &mut Self::Context) {
+        // snapshots queue up while the client doesn't keep up -> write only the newest once the mailbox is drained
+        if self.snapshot.replace(msg).is_none() {
+            ctx.spawn(fut::ready(()).map(|_, act: &mut Self, ctx: &mut ws::WebsocketContext<Self>| {
+                if let Some(snapshot) = act.snapshot.take() {
+                    if let Some(own) = snapshot.own {
+                        ctx.text(own);
+                    }
+                    ctx.text(snapshot.public);
+                }
+            }));
+        }
+    }
+}
+
+/// Follow the lobby moving us into another room
+impl Handler<JoinedRoom> for WsConn {
+    type Result = ();
+    fn handle(&mut self, msg: JoinedRoom, ctx: &mut Self::Context) {
+        self.room = msg.room_id;
+        self.game = msg.game;
+        self.span = session_span(self.id, self.room);
+        info!(parent: &self.span, "moved into room");
+        ctx.text(format!("joined room {}", self.room));
+    }
+}
+
+/// Close the websocket connection on request of the lobby
+impl Handler<WsClose> for WsConn {
+    type Result = ();
+    fn handle(&mut self, msg: WsClose, ctx: &mut Self::Context) {
+        ctx.close(msg.0);
+        ctx.stop();
+    }
+}
+
 impl WsConn {
-    /// Spawn heartbeat job every `interval` seconds + disconnect if didnt receive heartbeat for `timeout` seconds
+    /// Spawn heartbeat job every `interval` + disconnect if didnt receive heartbeat for `timeout`
     pub fn spawn_heartbeat_job(&self, ctx: &mut ws::WebsocketContext<Self>) {
-        ctx.run_interval(Duration::new(10, 0), |act, _ctx| {
-            if Instant::now().duration_since(act.hb).as_secs() > 60 * 2 {
+        ctx.run_interval(self.heartbeat.interval, |act, _ctx| {
+            if Instant::now().duration_since(act.hb) > act.heartbeat.timeout {
                 // heartbeat is dead
-                println!("Websocket heartbeat is dead -> Disconnecting");
-                act.lobby_addr.do_send(Disconnect {
-                    id: act.id,
-                    room_id: act.room,
-                });
-                _ctx.stop();
+                warn!(parent: &act.span, "heartbeat timed out, disconnecting");
+                _ctx.stop(); // stopping lets the lobby know
                 return;
             }
 
-            _ctx.ping(&[]);
+            // the client echoes the payload in the pong -> carry when we sent it to measure the round trip
+            let sent = act.created.elapsed().as_micros() as u64;
+            _ctx.ping(&sent.to_be_bytes());
+        });
+    }
+
+    /// Measure the round trip time from the timestamp our ping carried and let the lobby know
+    fn handle_pong(&mut self, payload: &[u8]) {
+        let sent = match <[u8; 8]>::try_from(payload) {
+            Ok(x) => Duration::from_micros(u64::from_be_bytes(x)),
+            Err(_) => return, // not one of our pings
+        };
+        let rtt = match self.created.elapsed().checked_sub(sent) {
+            Some(rtt) => rtt,
+            None => return,
+        };
+        self.rtt = Some(rtt);
+        self.lobby_addr.do_send(Latency {
+            id: self.id,
+            room_id: self.room,
+            rtt,
+            idle: self.last_message.elapsed(),
         });
     }
 }
@@ -109,33 +248,109 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsConn {
                 self.hb = Instant::now();
                 ctx.pong(&msg);
             }
-            Ok(ws::Message::Pong(_)) => {
+            Ok(ws::Message::Pong(payload)) => {
                 self.hb = Instant::now();
+                self.handle_pong(&payload);
             }
-            Ok(ws::Message::Binary(bin)) => ctx.binary(bin),
+            Ok(ws::Message::Binary(_)) => self.refuse_binary(ctx),
             Ok(ws::Message::Close(reason)) => {
                 ctx.close(reason);
                 ctx.stop();
             }
-            Ok(ws::Message::Continuation(_)) => {
-                ctx.stop();
-            }
+            Ok(ws::Message::Continuation(item)) => self.handle_continuation(item, ctx),
             Ok(ws::Message::Nop) => (),
-            Ok(Text(s)) => self
-                .lobby_addr
-                .try_send(ClientActorMessage {
-                    id: self.id,
-                    msg: s.to_string(),
-                    room_id: self.room,
-                })
-                .map_or_else(
-                    |err| {
-                        println!("Error sending message to lobby: {}", err);
-                        return ();
-                    },
-                    |x| (),
-                ),
-            Err(e) => panic!("{:?}", e),
+            Ok(Text(s)) => self.handle_text(&s, ctx),
+            Err(e) => {
+                // only this connection is broken -> close it with a fitting code
+                warn!(parent: &self.span, error = %e, "websocket protocol error");
+                let code = match e {
+                    ws::ProtocolError::Overflow => ws::CloseCode::Size,
+                    _ => ws::CloseCode::Protocol,
+                };
+                self.close(ctx, code, &e.to_string());
+            }
+        }
+    }
+}
+
+impl WsConn {
+    /// Close the connection to the client with close `code` and `description`
+    fn close(&self, ctx: &mut ws::WebsocketContext<Self>, code: ws::CloseCode, description: &str) {
+        ctx.close(Some(ws::CloseReason {
+            code,
+            description: Some(description.to_string()),
+        }));
+        ctx.stop();
+    }
+
+    /// Close the connection of a client sending binary messages, the protocol is text only
+    fn refuse_binary(&self, ctx: &mut ws::WebsocketContext<Self>) {
+        warn!(parent: &self.span, "binary message received, closing");
+        self.close(ctx, ws::CloseCode::Unsupported, "Binary messages are not supported")
+    }
+
+    /// Pass a text message of the client on to its room (inputs) or the lobby (chat and commands), unless the
+    /// client exceeds its rate limits
+    fn handle_text(&mut self, text: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        self.last_message = Instant::now();
+        let kind = MessageKind::of(text);
+        METRICS.messages.with_label_values(&[kind.label()]).inc();
+        match self.limiter.check(kind, Instant::now()) {
+            Verdict::Accept => (),
+            Verdict::Reject => return,
+            Verdict::Warn => return ctx.text("Slow down, you are sending too many messages"),
+            Verdict::Disconnect => {
+                warn!(parent: &self.span, rejected = self.limiter.rejected, "rate limits exceeded, disconnecting");
+                return self.close(ctx, ws::CloseCode::Policy, "Rate limit exceeded");
+            }
+        }
+        let msg = ClientActorMessage {
+            id: self.id,
+            msg: text.to_string(),
+            room_id: self.room,
+        };
+        // inputs are the bulk of the traffic, they go straight to the room and never hold up the lobby
+        if kind == MessageKind::Input {
+            return match &self.game {
+                Some(game) => game.try_send(msg).unwrap_or_else(|err| {
+                    METRICS.mailbox_failures.with_label_values(&["room"]).inc();
+                    warn!(parent: &self.span, error = %err, "input can't be passed on to the room")
+                }),
+                None => ctx.text(LobbyError::Spectating.to_string()),
+            };
+        }
+        self.lobby_addr.try_send(msg).unwrap_or_else(|err| {
+            METRICS.mailbox_failures.with_label_values(&["lobby"]).inc();
+            error!(parent: &self.span, error = %err, "message can't be passed on to the lobby")
+        });
+    }
+
+    /// Reassemble a message fragmented over continuation frames, handling it once complete
+    fn handle_continuation(&mut self, item: Item, ctx: &mut ws::WebsocketContext<Self>) {
+        let (first, last, data) = match item {
+            Item::FirstText(data) => (true, false, data),
+            Item::FirstBinary(_) => return self.refuse_binary(ctx),
+            Item::Continue(data) => (false, false, data),
+            Item::Last(data) => (false, true, data),
+        };
+        match (first, self.fragments.as_mut()) {
+            (true, None) => self.fragments = Some(data.to_vec()),
+            (false, Some(buffer)) => buffer.extend_from_slice(&data),
+            (true, Some(_)) => return self.close(ctx, ws::CloseCode::Protocol, "Continuation already started"),
+            (false, None) => return self.close(ctx, ws::CloseCode::Protocol, "Continuation not started"),
+        }
+        if self.fragments.as_ref().map_or(0, |buffer| buffer.len()) > MAX_MESSAGE_SIZE {
+            return self.close(ctx, ws::CloseCode::Size, "Message too big");
+        }
+        if !last {
+            return;
+        }
+
+        if let Some(buffer) = self.fragments.take() {
+            match String::from_utf8(buffer) {
+                Ok(text) => self.handle_text(&text, ctx),
+                Err(_) => self.close(ctx, ws::CloseCode::Invalid, "Text is not valid utf-8"),
+            }
         }
     }
 }
@@ -0,0 +1,33 @@
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use rustls_pemfile::Item;
+use std::{fs::File, io::BufReader, path::Path};
+
+use crate::config::ConfigError;
+
+fn open(path: &Path) -> Result<BufReader<File>, ConfigError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| ConfigError::Read(path.to_path_buf(), e))
+}
+
+/// Build the TLS settings from a PEM certificate chain and a PEM private key (PKCS#8, RSA or EC)
+pub fn load(cert: &Path, key: &Path) -> Result<ServerConfig, ConfigError> {
+    let invalid = |path: &Path, reason: &str| ConfigError::Tls(path.to_path_buf(), reason.to_string());
+    let certs = rustls_pemfile::certs(&mut open(cert)?).map_err(|e| invalid(cert, &e.to_string()))?;
+    if certs.is_empty() {
+        return Err(invalid(cert, "no certificate found"));
+    }
+    let private_key = rustls_pemfile::read_all(&mut open(key)?)
+        .map_err(|e| invalid(key, &e.to_string()))?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(x) | Item::RSAKey(x) | Item::ECKey(x) => Some(x),
+            _ => None,
+        })
+        .ok_or_else(|| invalid(key, "no private key found"))?;
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs.into_iter().map(Certificate).collect(), PrivateKey(private_key))
+        .map_err(|e| invalid(key, &e.to_string()))
+}